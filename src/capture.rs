@@ -0,0 +1,669 @@
+use crate::consumer::{hash_consumer_id, ConsumerHashing};
+
+use serde_json::{Map, Value};
+
+const DEFAULT_MAX_HEADER_LENGTH: usize = 256;
+const DEFAULT_MAX_BODY_BYTES: usize = 4096;
+const REDACTED: &str = "[REDACTED]";
+const DEFAULT_REDACT_FIELDS: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "access_token",
+    "refresh_token",
+    "api_key",
+    "apikey",
+    "authorization",
+    "credit_card",
+    "card_number",
+    "cvv",
+    "ssn",
+];
+
+/// How a captured header value is transformed before it is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderRedaction {
+    /// Store the value as-is (subject to the length cap).
+    #[default]
+    None,
+    /// Record that the header was present, but not its value.
+    Mask,
+    /// Store a hash of the value: an HMAC when the client has
+    /// `consumer_hashing` configured (`hmac_<hex>`), otherwise SHA-256
+    /// (`hash_<hex>`).
+    Hash,
+}
+
+/// A single header to copy into event metadata.
+#[derive(Debug, Clone)]
+pub struct CapturedHeader {
+    /// Header name (case-insensitive).
+    pub name: String,
+    /// Redaction applied to the value.
+    pub redaction: HeaderRedaction,
+    /// Maximum stored value length in bytes. Default: 256.
+    pub max_length: usize,
+}
+
+impl CapturedHeader {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into().to_lowercase(),
+            redaction: HeaderRedaction::None,
+            max_length: DEFAULT_MAX_HEADER_LENGTH,
+        }
+    }
+
+    /// Set the redaction mode for this header.
+    pub fn redact(mut self, redaction: HeaderRedaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Set the maximum stored value length.
+    pub fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+}
+
+impl From<&str> for CapturedHeader {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+/// Request and response headers to capture into `RequestEvent.metadata`.
+///
+/// Captured values are stored under a structured `headers` key:
+///
+/// ```json
+/// { "headers": { "request": { "user-agent": "..." }, "response": { "x-cache": "HIT" } } }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HeaderCapture {
+    pub request: Vec<CapturedHeader>,
+    pub response: Vec<CapturedHeader>,
+    // The client's `consumer_hashing`, used for `HeaderRedaction::Hash`
+    pub(crate) hashing: Option<ConsumerHashing>,
+}
+
+impl HeaderCapture {
+    /// Add a request header to capture.
+    pub fn request(mut self, header: impl Into<CapturedHeader>) -> Self {
+        self.request.push(header.into());
+        self
+    }
+
+    /// Add a response header to capture.
+    pub fn response(mut self, header: impl Into<CapturedHeader>) -> Self {
+        self.response.push(header.into());
+        self
+    }
+
+    /// Collect the configured request headers using a header getter.
+    pub fn capture_request<F>(&self, get_header: F) -> Map<String, Value>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        capture(&self.request, self.hashing.as_ref(), get_header)
+    }
+
+    /// Collect the configured response headers using a header getter.
+    pub fn capture_response<F>(&self, get_header: F) -> Map<String, Value>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        capture(&self.response, self.hashing.as_ref(), get_header)
+    }
+
+    /// Build the `headers` metadata value from captured request and response
+    /// headers. Returns `None` when nothing was captured.
+    pub fn to_metadata(request: Map<String, Value>, response: Map<String, Value>) -> Option<Value> {
+        if request.is_empty() && response.is_empty() {
+            return None;
+        }
+        let mut headers = Map::new();
+        if !request.is_empty() {
+            headers.insert("request".to_string(), Value::Object(request));
+        }
+        if !response.is_empty() {
+            headers.insert("response".to_string(), Value::Object(response));
+        }
+        Some(Value::Object(headers))
+    }
+}
+
+fn capture<F>(
+    spec: &[CapturedHeader],
+    hashing: Option<&ConsumerHashing>,
+    get_header: F,
+) -> Map<String, Value>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = Map::new();
+    for header in spec {
+        let Some(value) = get_header(&header.name) else {
+            continue;
+        };
+        let stored = match header.redaction {
+            HeaderRedaction::None => truncate(value, header.max_length),
+            HeaderRedaction::Mask => REDACTED.to_string(),
+            HeaderRedaction::Hash => match hashing {
+                Some(h) => h.hash(&value),
+                None => hash_consumer_id(&value),
+            },
+        };
+        out.insert(header.name.clone(), Value::String(stored));
+    }
+    out
+}
+
+/// Opt-in request/response body capture for failed calls.
+///
+/// Supported by the Actix Web and Axum adapters. Up to `max_bytes` of
+/// JSON/text/form bodies are teed while they stream through, so handlers and
+/// clients see the body unchanged. When the response status is at least
+/// `min_status`, the captured bodies are redacted and stored under a `body`
+/// metadata key:
+///
+/// ```json
+/// { "body": { "request": { "content_type": "application/json", "data": "{...}", "truncated": false } } }
+/// ```
+///
+/// Bodies are the first thing dropped when an event exceeds `max_event_bytes`.
+#[derive(Debug, Clone)]
+pub struct BodyCapture {
+    /// Path prefixes to capture on. Empty captures on every route.
+    pub routes: Vec<String>,
+    /// Minimum response status that keeps the captured bodies. Default: 400.
+    pub min_status: u16,
+    /// Maximum bytes captured per body. Default: 4096.
+    pub max_bytes: usize,
+    /// Capture request bodies. Default: true.
+    pub request: bool,
+    /// Capture response bodies. Default: true.
+    pub response: bool,
+    /// Field names whose values are replaced with `[REDACTED]`
+    /// (case-insensitive): JSON keys, form fields, XML elements and
+    /// attributes, and `name=value` / `name: value` pairs in text bodies.
+    /// Defaults to common credential and PII field names.
+    pub redact_fields: Vec<String>,
+}
+
+impl Default for BodyCapture {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            min_status: 400,
+            max_bytes: DEFAULT_MAX_BODY_BYTES,
+            request: true,
+            response: true,
+            redact_fields: DEFAULT_REDACT_FIELDS
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl BodyCapture {
+    /// Restrict capture to paths starting with `prefix`.
+    pub fn route(mut self, prefix: impl Into<String>) -> Self {
+        self.routes.push(prefix.into());
+        self
+    }
+
+    /// Set the minimum response status that keeps captured bodies.
+    pub fn min_status(mut self, status: u16) -> Self {
+        self.min_status = status;
+        self
+    }
+
+    /// Set the maximum bytes captured per body.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Add a field name to redact.
+    pub fn redact_field(mut self, field: impl Into<String>) -> Self {
+        self.redact_fields.push(field.into());
+        self
+    }
+
+    /// Whether capture is enabled for the given request path.
+    pub fn matches_route(&self, path: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|r| path.starts_with(r.as_str()))
+    }
+
+    /// Whether bodies with this content type are captured (JSON, text, XML, forms).
+    pub fn is_capturable(content_type: &str) -> bool {
+        let ct = content_type.to_ascii_lowercase();
+        ct.starts_with("text/")
+            || ct.contains("json")
+            || ct.contains("xml")
+            || ct.starts_with("application/x-www-form-urlencoded")
+    }
+
+    /// Redact a captured body and build its metadata value.
+    pub fn render(&self, content_type: &str, bytes: &[u8], truncated: bool) -> Value {
+        let text = String::from_utf8_lossy(bytes);
+        let data = if content_type.to_ascii_lowercase().contains("json") {
+            match serde_json::from_str::<Value>(&text) {
+                Ok(mut json) if !truncated => {
+                    self.redact_json(&mut json);
+                    json.to_string()
+                }
+                _ => self.redact_json_text(&text),
+            }
+        } else if content_type
+            .to_ascii_lowercase()
+            .starts_with("application/x-www-form-urlencoded")
+        {
+            self.redact_form(&text)
+        } else if content_type.to_ascii_lowercase().contains("xml") {
+            self.redact_xml(&text)
+        } else {
+            self.redact_pairs(&text)
+        };
+
+        let mut out = Map::new();
+        out.insert("content_type".to_string(), Value::from(content_type));
+        out.insert("data".to_string(), Value::String(data));
+        out.insert("truncated".to_string(), Value::Bool(truncated));
+        Value::Object(out)
+    }
+
+    fn is_redacted(&self, field: &str) -> bool {
+        self.redact_fields
+            .iter()
+            .any(|f| f.eq_ignore_ascii_case(field))
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, v) in map.iter_mut() {
+                    if self.is_redacted(key) {
+                        *v = Value::from(REDACTED);
+                    } else {
+                        self.redact_json(v);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact_json(v)),
+            _ => {}
+        }
+    }
+
+    /// Best-effort redaction of `"field": "value"` pairs in JSON that could
+    /// not be parsed (typically because it was truncated).
+    fn redact_json_text(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for field in &self.redact_fields {
+            let needle = format!("\"{}\"", field.to_ascii_lowercase());
+            let mut search_from = 0;
+            loop {
+                let lower = out.to_ascii_lowercase();
+                let Some(pos) = lower[search_from..].find(&needle) else {
+                    break;
+                };
+                let after_key = search_from + pos + needle.len();
+                let rest = &out[after_key..];
+                let trimmed = rest.trim_start();
+                if !trimmed.starts_with(':') {
+                    search_from = after_key;
+                    continue;
+                }
+                let value_start = after_key + (rest.len() - trimmed.len()) + 1;
+                let value_start = value_start
+                    + (out[value_start..].len() - out[value_start..].trim_start().len());
+                let value_end = json_value_end(&out, value_start);
+                let replacement = format!("\"{REDACTED}\"");
+                out.replace_range(value_start..value_end, &replacement);
+                search_from = value_start + replacement.len();
+            }
+        }
+        out
+    }
+
+    fn redact_form(&self, text: &str) -> String {
+        text.split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if self.is_redacted(name) => format!("{name}={REDACTED}"),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Redact the content of XML elements and the values of attributes whose
+    /// local name (without namespace prefix) is a redacted field. A truncated
+    /// element is redacted to the end of the text.
+    fn redact_xml(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(open) = rest.find('<') {
+            out.push_str(&rest[..open]);
+            rest = &rest[open..];
+            let Some(close) = rest.find('>') else {
+                out.push_str(&self.redact_xml_attributes(rest));
+                return out;
+            };
+            let tag = &rest[..=close];
+            rest = &rest[close + 1..];
+            out.push_str(&self.redact_xml_attributes(tag));
+
+            let inner = &tag[1..tag.len() - 1];
+            if inner.starts_with(['/', '!', '?']) || inner.ends_with('/') {
+                continue;
+            }
+            let name = inner.split(char::is_whitespace).next().unwrap_or_default();
+            if !self.is_redacted(local_name(name)) {
+                continue;
+            }
+            let end_tag = format!("</{}", name.to_ascii_lowercase());
+            let end = rest
+                .to_ascii_lowercase()
+                .find(&end_tag)
+                .unwrap_or(rest.len());
+            out.push_str(REDACTED);
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Redact quoted attribute values inside a single tag.
+    fn redact_xml_attributes(&self, tag: &str) -> String {
+        let mut out = String::with_capacity(tag.len());
+        let mut rest = tag;
+        while let Some(eq) = rest.find('=') {
+            let (before, after) = (&rest[..eq], &rest[eq + 1..]);
+            out.push_str(before);
+            out.push('=');
+            let name = before
+                .trim_end()
+                .rsplit(char::is_whitespace)
+                .next()
+                .unwrap_or_default();
+            let value = after.trim_start();
+            let Some(quote) = value.chars().next().filter(|c| matches!(c, '"' | '\'')) else {
+                rest = after;
+                continue;
+            };
+            let value_start = after.len() - value.len() + 1;
+            let value_end = after[value_start..]
+                .find(quote)
+                .map_or(after.len(), |p| value_start + p);
+            out.push_str(&after[..value_start]);
+            if self.is_redacted(local_name(name)) {
+                out.push_str(REDACTED);
+            } else {
+                out.push_str(&after[value_start..value_end]);
+            }
+            rest = &after[value_end..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Redact `name=value` and `name: value` pairs in free text. `=` values
+    /// end at whitespace, `&`, `;` or `,`; `:` values run to the end of the
+    /// line (as in `Authorization: Bearer ...`). Quoted values end at the
+    /// closing quote.
+    fn redact_pairs(&self, text: &str) -> String {
+        let bytes = text.as_bytes();
+        let is_name_byte = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'-';
+        let mut out = String::with_capacity(text.len());
+        let mut copied = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            if !is_name_byte(bytes[i]) || (i > 0 && is_name_byte(bytes[i - 1])) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < bytes.len() && is_name_byte(bytes[i]) {
+                i += 1;
+            }
+            let name = &text[start..i];
+            let mut j = i;
+            while j < bytes.len() && matches!(bytes[j], b' ' | b'\t') {
+                j += 1;
+            }
+            let Some(&separator) = bytes.get(j).filter(|b| matches!(b, b'=' | b':')) else {
+                continue;
+            };
+            if !self.is_redacted(name) {
+                continue;
+            }
+            j += 1;
+            while j < bytes.len() && matches!(bytes[j], b' ' | b'\t') {
+                j += 1;
+            }
+            let end = match bytes.get(j) {
+                Some(&quote @ (b'"' | b'\'')) => bytes[j + 1..]
+                    .iter()
+                    .position(|&b| b == quote)
+                    .map_or(bytes.len(), |p| j + p + 2),
+                _ if separator == b':' => bytes[j..]
+                    .iter()
+                    .position(|&b| matches!(b, b'\r' | b'\n'))
+                    .map_or(bytes.len(), |p| j + p),
+                _ => bytes[j..]
+                    .iter()
+                    .position(|&b| b.is_ascii_whitespace() || matches!(b, b'&' | b';' | b','))
+                    .map_or(bytes.len(), |p| j + p),
+            };
+            if end > j {
+                out.push_str(&text[copied..j]);
+                out.push_str(REDACTED);
+                copied = end;
+            }
+            i = end.max(j);
+        }
+        out.push_str(&text[copied..]);
+        out
+    }
+}
+
+/// XML name without its namespace prefix.
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// End offset of the JSON scalar starting at `start`: a string (honoring
+/// escapes) or a bare token ending at `,` `}` `]`. Unterminated values run to
+/// the end of the text.
+fn json_value_end(text: &str, start: usize) -> usize {
+    let bytes = text.as_bytes();
+    if bytes.get(start) == Some(&b'"') {
+        let mut i = start + 1;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 2,
+                b'"' => return i + 1,
+                _ => i += 1,
+            }
+        }
+        return bytes.len();
+    }
+    bytes[start..]
+        .iter()
+        .position(|b| matches!(b, b',' | b'}' | b']'))
+        .map_or(bytes.len(), |p| start + p)
+}
+
+/// Truncate a string to at most `max` bytes on a char boundary.
+pub(crate) fn truncate(mut value: String, max: usize) -> String {
+    if value.len() > max {
+        let mut end = max;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &str) -> Option<String> {
+        match name {
+            "user-agent" => Some("peekapi-test/1.0".to_string()),
+            "authorization" => Some("Bearer secret".to_string()),
+            "x-request-id" => Some("r".repeat(500)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn captures_configured_headers_only() {
+        let capture = HeaderCapture::default()
+            .request("User-Agent")
+            .request("accept-language");
+        let map = capture.capture_request(headers);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["user-agent"], "peekapi-test/1.0");
+    }
+
+    #[test]
+    fn applies_redaction_and_length_cap() {
+        let capture = HeaderCapture::default()
+            .request(CapturedHeader::new("authorization").redact(HeaderRedaction::Hash))
+            .request(CapturedHeader::new("x-request-id").max_length(8));
+        let map = capture.capture_request(headers);
+        assert!(map["authorization"].as_str().unwrap().starts_with("hash_"));
+        assert_eq!(map["x-request-id"], "rrrrrrrr");
+
+        let masked = HeaderCapture::default()
+            .request(CapturedHeader::new("authorization").redact(HeaderRedaction::Mask))
+            .capture_request(headers);
+        assert_eq!(masked["authorization"], REDACTED);
+    }
+
+    #[test]
+    fn hash_redaction_uses_consumer_hashing() {
+        let hashing = ConsumerHashing::new("s3cret");
+        let mut capture = HeaderCapture::default()
+            .request(CapturedHeader::new("authorization").redact(HeaderRedaction::Hash));
+        capture.hashing = Some(hashing.clone());
+        let map = capture.capture_request(headers);
+        let expected = hashing.hash(&headers("authorization").unwrap());
+        assert_eq!(map["authorization"], expected.as_str());
+        assert_ne!(
+            map["authorization"],
+            hash_consumer_id(&headers("authorization").unwrap()).as_str()
+        );
+    }
+
+    #[test]
+    fn to_metadata_skips_empty() {
+        assert!(HeaderCapture::to_metadata(Map::new(), Map::new()).is_none());
+        let mut response = Map::new();
+        response.insert("x-cache".to_string(), Value::from("HIT"));
+        let meta = HeaderCapture::to_metadata(Map::new(), response).unwrap();
+        assert_eq!(meta["response"]["x-cache"], "HIT");
+        assert!(meta.get("request").is_none());
+    }
+
+    #[test]
+    fn body_route_and_content_type_filters() {
+        let capture = BodyCapture::default().route("/api/payments");
+        assert!(capture.matches_route("/api/payments/123"));
+        assert!(!capture.matches_route("/api/users"));
+        assert!(BodyCapture::default().matches_route("/anything"));
+
+        assert!(BodyCapture::is_capturable(
+            "application/json; charset=utf-8"
+        ));
+        assert!(BodyCapture::is_capturable("text/plain"));
+        assert!(BodyCapture::is_capturable("application/problem+json"));
+        assert!(!BodyCapture::is_capturable("image/png"));
+        assert!(!BodyCapture::is_capturable("application/octet-stream"));
+    }
+
+    #[test]
+    fn body_render_redacts_json_fields() {
+        let capture = BodyCapture::default();
+        let body = br#"{"user":"ada","password":"hunter2","nested":[{"Token":"abc"}]}"#;
+        let rendered = capture.render("application/json", body, false);
+        let data: Value = serde_json::from_str(rendered["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data["user"], "ada");
+        assert_eq!(data["password"], REDACTED);
+        assert_eq!(data["nested"][0]["Token"], REDACTED);
+        assert_eq!(rendered["truncated"], false);
+    }
+
+    #[test]
+    fn body_render_redacts_truncated_json_text() {
+        let capture = BodyCapture::default();
+        let body = br#"{"password": "hunter\"2", "user":"ada", "token":"abcdef"#;
+        let rendered = capture.render("application/json", body, true);
+        let data = rendered["data"].as_str().unwrap();
+        assert!(!data.contains("hunter"));
+        assert!(!data.contains("abcdef"));
+        assert!(data.contains(r#""user":"ada""#));
+        assert_eq!(rendered["truncated"], true);
+    }
+
+    #[test]
+    fn body_render_redacts_form_fields() {
+        let capture = BodyCapture::default().redact_field("pin");
+        let rendered = capture.render(
+            "application/x-www-form-urlencoded",
+            b"user=ada&password=x&pin=1234",
+            false,
+        );
+        assert_eq!(
+            rendered["data"],
+            "user=ada&password=[REDACTED]&pin=[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn body_render_redacts_xml_elements_and_attributes() {
+        let capture = BodyCapture::default();
+        let rendered = capture.render(
+            "application/soap+xml",
+            br#"<login user="ada" token='t0k'><ns:Password>hunter2</ns:Password><pin/></login>"#,
+            false,
+        );
+        assert_eq!(
+            rendered["data"],
+            r#"<login user="ada" token='[REDACTED]'><ns:Password>[REDACTED]</ns:Password><pin/></login>"#
+        );
+
+        // Truncated inside a redacted element
+        let rendered = capture.render("text/xml", b"<a><secret>abc", true);
+        assert_eq!(rendered["data"], "<a><secret>[REDACTED]");
+    }
+
+    #[test]
+    fn body_render_redacts_text_pairs() {
+        let capture = BodyCapture::default();
+        let rendered = capture.render(
+            "text/plain",
+            b"user=ada password=hunter2&x=1\nAuthorization: Bearer abc.def\nsecret: \"a b\", mytoken=keep",
+            false,
+        );
+        assert_eq!(
+            rendered["data"],
+            "user=ada password=[REDACTED]&x=1\nAuthorization: [REDACTED]\nsecret: [REDACTED], mytoken=keep"
+        );
+        let rendered = capture.render("text/html", b"<p>no pairs here</p>", false);
+        assert_eq!(rendered["data"], "<p>no pairs here</p>");
+    }
+
+    #[test]
+    fn truncate_respects_char_boundary() {
+        assert_eq!(truncate("héllo".to_string(), 2), "h");
+        assert_eq!(truncate("abc".to_string(), 10), "abc");
+    }
+}
@@ -1,10 +1,31 @@
-use crate::ssrf::validate_endpoint;
-use crate::types::{ErrorCallback, IdentifyConsumerFn, Options, RequestEvent};
-
+use crate::capture::{BodyCapture, HeaderCapture};
+use crate::client_ip::ClientIpConfig;
+use crate::consumer::{
+    default_identify_consumer, default_identify_consumer_with, ConsumerChain, ConsumerIdPolicy,
+};
+use crate::dead_letter::{DeadLetter, DeadLetterReason, DeadLetterStore};
+use crate::format::{decode_records, PayloadFormat};
+use crate::idempotency::{new_id, Batch, IDEMPOTENCY_KEY_HEADER};
+use crate::query::{normalize_query, QueryStringPolicy};
+use crate::request::RequestView;
+use crate::response::{parse_rejections, Delivery};
+use crate::signing::{RequestSigning, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::ssrf::{
+    endpoint_host_port, resolve_and_validate, validate_endpoint_with, EndpointPolicy, Resolver,
+    SystemResolver,
+};
+use crate::tls::TlsOptions;
+use crate::transport::{is_valid_header_value, ProxySettings, Transport, TransportConfig};
+use crate::types::{
+    ApiKeyProviderFn, ErrorCallback, IdentifyConsumerFn, IdentifyRequestFn, Options, RequestEvent,
+};
+
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{self, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
 const DEFAULT_ENDPOINT: &str = "https://ingest.peekapi.dev/v1/events";
@@ -13,18 +34,43 @@ const MAX_METHOD_LENGTH: usize = 16;
 const MAX_CONSUMER_ID_LENGTH: usize = 256;
 const MAX_CONSECUTIVE_FAILURES: u32 = 5;
 const BASE_BACKOFF: Duration = Duration::from_secs(1);
-const SEND_TIMEOUT: Duration = Duration::from_secs(5);
 const DISK_RECOVERY_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest ingestion response body read for per-event results.
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// Headers set by the client or the HTTP stack, which `Options::headers`
+/// may not override. Every `x-peekapi-*` name is reserved as well.
+const RESERVED_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "host",
+    "user-agent",
+    "proxy-authorization",
+    "x-api-key",
+    "idempotency-key",
+];
 
 struct Inner {
     buffer: Vec<RequestEvent>,
     spare: Vec<RequestEvent>,
+    // Batches that failed (or were recovered from disk), sent before the
+    // buffer under their original IDs
+    pending: VecDeque<Batch>,
     consecutive_failures: u32,
     backoff_until: Instant,
     flush_in_flight: bool,
     wake: bool, // condvar predicate — set when flush or shutdown is requested
 }
 
+impl Inner {
+    /// Events waiting to be sent: the buffer and the pending batches.
+    fn queued(&self) -> usize {
+        self.buffer.len() + self.pending.iter().map(|b| b.events.len()).sum::<usize>()
+    }
+}
+
 /// Buffered analytics client.
 ///
 /// Events are accumulated in memory and flushed to the ingestion endpoint
@@ -35,24 +81,49 @@ pub struct PeekApiClient {
     cond: Condvar,
     closed: AtomicBool,
     opts: ClientOpts,
+    /// Current ingestion API key, replaced by `set_api_key` or the provider.
+    api_key: RwLock<String>,
+    transport: Transport,
+    /// Set when the endpoint answered 415 to `payload_format`.
+    json_fallback: AtomicBool,
+    /// Events the ingestion API will never accept.
+    dead_letters: DeadLetterStore,
+    /// Events dropped because the ingestion API rejected them permanently.
+    rejected_events: AtomicU64,
     // Background thread handle — joined on shutdown
     thread: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 /// Immutable configuration extracted from Options (includes callbacks).
 struct ClientOpts {
-    api_key: String,
+    api_key_provider: Option<ApiKeyProviderFn>,
     endpoint: String,
+    endpoint_policy: EndpointPolicy,
+    max_redirects: u32,
+    headers: Vec<(String, String)>,
+    signing: Option<RequestSigning>,
+    proxy: Option<ProxySettings>,
+    /// Resolver for re-checking the endpoint host before each request when
+    /// `dns_validation` is on but connections go through a proxy.
+    proxied_dns_check: Option<Arc<dyn Resolver>>,
+    payload_format: PayloadFormat,
+    storage_format: PayloadFormat,
     flush_interval: Duration,
     batch_size: usize,
     max_buffer_size: usize,
     max_storage_bytes: u64,
     max_event_bytes: usize,
-    collect_query_string: bool,
+    query_string: Option<QueryStringPolicy>,
+    capture_headers: Option<HeaderCapture>,
+    capture_body: Option<BodyCapture>,
+    client_ip: Option<ClientIpConfig>,
     debug: bool,
     storage_path: String,
     on_error: Option<ErrorCallback>,
+    consumer_id_policy: ConsumerIdPolicy,
+    consumer_chain: Option<ConsumerChain>,
     identify_consumer: Option<IdentifyConsumerFn>,
+    identify_request: Option<IdentifyRequestFn>,
 }
 
 impl PeekApiClient {
@@ -61,12 +132,13 @@ impl PeekApiClient {
     /// Validates the configuration, loads any previously persisted events
     /// from disk, and starts a background thread for periodic flushing.
     pub fn new(opts: Options) -> Result<Arc<Self>, String> {
-        if opts.api_key.is_empty() {
-            return Err("[peekapi] 'api_key' is required".to_string());
-        }
-        if opts.api_key.contains('\0') || opts.api_key.contains('\r') || opts.api_key.contains('\n')
-        {
-            return Err("[peekapi] 'api_key' contains invalid characters".to_string());
+        let api_key = match opts.api_key_provider {
+            Some(ref provider) if opts.api_key.is_empty() => provider().unwrap_or_default(),
+            _ => opts.api_key.clone(),
+        };
+        validate_api_key(&api_key)?;
+        if opts.signing.as_ref().is_some_and(|s| s.secret.is_empty()) {
+            return Err("[peekapi] 'signing' secret must not be empty".to_string());
         }
 
         let raw_endpoint = if opts.endpoint.is_empty() {
@@ -74,7 +146,37 @@ impl PeekApiClient {
         } else {
             opts.endpoint.clone()
         };
-        let endpoint = validate_endpoint(&raw_endpoint)?;
+        let endpoint = validate_endpoint_with(&raw_endpoint, &opts.endpoint_policy)?;
+        if opts.dns_validation {
+            let (host, port) = endpoint_host_port(&endpoint)?;
+            let policy = &opts.endpoint_policy;
+            match opts.resolver {
+                Some(ref resolver) => resolve_and_validate(&host, port, &**resolver, policy)?,
+                None => resolve_and_validate(&host, port, &SystemResolver, policy)?,
+            };
+        }
+        let proxy = match opts.proxy {
+            Some(ref proxy) => proxy.resolve(&endpoint)?,
+            None => None,
+        };
+        let proxied_dns_check = (opts.dns_validation && proxy.is_some()).then(|| {
+            opts.resolver
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemResolver) as Arc<dyn Resolver>)
+        });
+        opts.transport.validate()?;
+        let transport = Transport::new(TransportConfig {
+            resolver: opts.resolver.clone(),
+            dns_validation: opts.dns_validation,
+            endpoint_policy: opts.endpoint_policy.clone(),
+            proxy: proxy.clone(),
+            tls: opts
+                .tls
+                .as_ref()
+                .map(TlsOptions::client_config)
+                .transpose()?,
+            options: opts.transport.clone(),
+        });
 
         let storage_path = opts.storage_path.unwrap_or_else(|| {
             use sha2::{Digest, Sha256};
@@ -86,6 +188,16 @@ impl PeekApiClient {
                 .to_string()
         });
 
+        let dead_letters = DeadLetterStore::new(
+            opts.dead_letter_path
+                .unwrap_or_else(|| dead_letter_path(&storage_path)),
+            if opts.max_dead_letter_bytes == 0 {
+                1_048_576
+            } else {
+                opts.max_dead_letter_bytes
+            },
+        );
+
         let batch_size = if opts.batch_size == 0 {
             100
         } else {
@@ -93,8 +205,16 @@ impl PeekApiClient {
         };
 
         let client_opts = ClientOpts {
-            api_key: opts.api_key,
+            api_key_provider: opts.api_key_provider,
             endpoint,
+            endpoint_policy: opts.endpoint_policy,
+            max_redirects: opts.max_redirects,
+            headers: custom_headers(opts.headers)?,
+            signing: opts.signing,
+            proxy,
+            proxied_dns_check,
+            payload_format: opts.payload_format,
+            storage_format: opts.storage_format,
             flush_interval: opts.flush_interval,
             batch_size,
             max_buffer_size: if opts.max_buffer_size == 0 {
@@ -112,16 +232,32 @@ impl PeekApiClient {
             } else {
                 opts.max_event_bytes
             },
-            collect_query_string: opts.collect_query_string,
+            query_string: opts
+                .query_string
+                .or_else(|| opts.collect_query_string.then(QueryStringPolicy::default)),
+            capture_headers: opts.capture_headers.map(|mut capture| {
+                capture.hashing = opts.consumer_hashing.clone();
+                capture
+            }),
+            capture_body: opts.capture_body,
+            client_ip: opts.client_ip.map(ClientIpConfig::new).transpose()?,
             debug: opts.debug,
             storage_path,
             on_error: opts.on_error,
+            consumer_id_policy: ConsumerIdPolicy {
+                api_key: opts.api_key_policy,
+                hashing: opts.consumer_hashing,
+                jwt: opts.jwt_identifier,
+            },
+            consumer_chain: opts.consumer_chain,
             identify_consumer: opts.identify_consumer,
+            identify_request: opts.identify_request,
         };
 
         let inner = Inner {
             buffer: Vec::with_capacity(batch_size),
             spare: Vec::with_capacity(batch_size),
+            pending: VecDeque::new(),
             consecutive_failures: 0,
             backoff_until: Instant::now(),
             flush_in_flight: false,
@@ -132,7 +268,12 @@ impl PeekApiClient {
             inner: Mutex::new(inner),
             cond: Condvar::new(),
             closed: AtomicBool::new(false),
+            transport,
             opts: client_opts,
+            api_key: RwLock::new(api_key),
+            rejected_events: AtomicU64::new(0),
+            json_fallback: AtomicBool::new(false),
+            dead_letters,
             thread: Mutex::new(None),
         });
 
@@ -176,25 +317,40 @@ impl PeekApiClient {
         if event.timestamp.is_empty() {
             event.timestamp = now_iso8601();
         }
+        if event.event_id.is_empty() {
+            event.event_id = new_id();
+        }
 
         // Per-event size limit
         if let Ok(raw) = serde_json::to_vec(&event) {
             if raw.len() > self.opts.max_event_bytes {
-                // Strip metadata and retry
-                event.metadata = None;
-                if let Ok(raw2) = serde_json::to_vec(&event) {
-                    if raw2.len() > self.opts.max_event_bytes {
-                        if self.opts.debug {
-                            eprintln!("[peekapi] Event too large, dropping ({} bytes)", raw2.len());
+                // Drop captured bodies first, then all metadata
+                let mut fits = false;
+                if let Some(serde_json::Value::Object(ref mut meta)) = event.metadata {
+                    if meta.remove("body").is_some() {
+                        fits = serde_json::to_vec(&event)
+                            .is_ok_and(|raw| raw.len() <= self.opts.max_event_bytes);
+                    }
+                }
+                if !fits {
+                    event.metadata = None;
+                    if let Ok(raw2) = serde_json::to_vec(&event) {
+                        if raw2.len() > self.opts.max_event_bytes {
+                            if self.opts.debug {
+                                eprintln!(
+                                    "[peekapi] Event too large, dropping ({} bytes)",
+                                    raw2.len()
+                                );
+                            }
+                            return;
                         }
-                        return;
                     }
                 }
             }
         }
 
         let mut guard = self.inner.lock().unwrap();
-        if guard.buffer.len() >= self.opts.max_buffer_size {
+        if guard.queued() >= self.opts.max_buffer_size {
             // Buffer full — signal flush
             guard.wake = true;
             self.cond.notify_one();
@@ -213,89 +369,206 @@ impl PeekApiClient {
     }
 
     /// Flush buffered events synchronously. Respects in-flight and backoff guards.
+    ///
+    /// Batches waiting for a retry go first, each under its original ID,
+    /// then the buffer as a new batch. Stops at the first failure.
     pub fn flush(&self) {
-        let events = {
-            let mut guard = self.inner.lock().unwrap();
-            if guard.flush_in_flight {
+        let mut take_buffer = true;
+        while let Some((batch, from_buffer)) = self.next_batch(take_buffer) {
+            take_buffer &= !from_buffer;
+            if !self.deliver(batch) {
                 return;
             }
-            if guard.consecutive_failures > 0 && Instant::now() < guard.backoff_until {
-                return;
-            }
-            if guard.buffer.is_empty() {
-                return;
-            }
-            guard.flush_in_flight = true;
-
-            // Double-buffer swap: take spare first to avoid double borrow
-            let spare = std::mem::take(&mut guard.spare);
+        }
+    }
 
-            std::mem::replace(&mut guard.buffer, spare)
+    /// Take the next batch to send, and whether it is the buffer, or `None`
+    /// if nothing is due.
+    fn next_batch(&self, take_buffer: bool) -> Option<(Batch, bool)> {
+        let mut guard = self.inner.lock().unwrap();
+        if guard.flush_in_flight {
+            return None;
+        }
+        if guard.consecutive_failures > 0 && Instant::now() < guard.backoff_until {
+            return None;
+        }
+        let next = match guard.pending.pop_front() {
+            Some(batch) => (batch, false),
+            None if !take_buffer || guard.buffer.is_empty() => return None,
+            None => {
+                // Double-buffer swap: take spare first to avoid double borrow
+                let spare = std::mem::take(&mut guard.spare);
+                (
+                    Batch::new(std::mem::replace(&mut guard.buffer, spare)),
+                    true,
+                )
+            }
         };
+        guard.flush_in_flight = true;
+        Some(next)
+    }
 
-        let event_count = events.len();
-        let result = self.send(&events);
+    /// Send one batch and act on the result. Returns whether the batch was
+    /// settled and the next one may be sent.
+    fn deliver(&self, batch: Batch) -> bool {
+        let event_count = batch.events.len();
+        let result = self.send(&batch);
 
         let mut guard = self.inner.lock().unwrap();
         guard.flush_in_flight = false;
 
         match result {
-            Ok(()) => {
+            Ok(delivery) if delivery.is_complete() => {
                 guard.consecutive_failures = 0;
                 guard.backoff_until = Instant::now();
                 if self.opts.debug {
                     eprintln!("[peekapi] Flushed {event_count} events");
                 }
                 // Recycle the events vec as spare
-                let mut recycled = events;
+                let mut recycled = batch.events;
                 recycled.clear();
                 if guard.spare.is_empty() {
                     guard.spare = recycled;
                 }
+                true
             }
-            Err(ref e) if !is_retryable(e) => {
+            Ok(delivery) => {
                 drop(guard);
-                self.persist_to_disk(&events);
-                if self.opts.debug {
-                    eprintln!("[peekapi] Non-retryable error, persisted to disk: {e}");
+                let Batch { id, events } = batch;
+                let (dropped, retry) = delivery.split(events);
+                if !dropped.is_empty() {
+                    self.report_rejected(dropped);
                 }
-                self.call_on_error(e);
-            }
-            Err(ref e) => {
-                guard.consecutive_failures += 1;
-                let failures = guard.consecutive_failures;
-
-                if failures >= MAX_CONSECUTIVE_FAILURES {
+                if retry.is_empty() {
+                    let mut guard = self.inner.lock().unwrap();
                     guard.consecutive_failures = 0;
-                    drop(guard);
-                    self.persist_to_disk(&events);
+                    guard.backoff_until = Instant::now();
+                    return true;
+                }
+                let err = SendError::new(
+                    format!(
+                        "Ingestion API deferred {} of {event_count} events",
+                        retry.len()
+                    ),
+                    true,
+                );
+                // A different set of events than the ID was sent with goes
+                // out as a new batch
+                let retry = if retry.len() == event_count {
+                    Batch { id, events: retry }
                 } else {
-                    // Re-insert events at the front
-                    let space = self.opts.max_buffer_size.saturating_sub(guard.buffer.len());
-                    let reinsert_count = events.len().min(space);
-                    if reinsert_count > 0 {
-                        let mut merged = Vec::with_capacity(reinsert_count + guard.buffer.len());
-                        merged.extend_from_slice(&events[..reinsert_count]);
-                        merged.append(&mut guard.buffer);
-                        guard.buffer = merged;
-                    }
-
-                    // Exponential backoff with jitter
-                    let base = BASE_BACKOFF * (1 << (failures - 1));
-                    let jitter = 0.5 + rand_f64() * 0.5;
-                    let delay = Duration::from_secs_f64(base.as_secs_f64() * jitter);
-                    guard.backoff_until = Instant::now() + delay;
-                    drop(guard);
+                    Batch::new(retry)
+                };
+                self.retry_later(retry, &err);
+                false
+            }
+            Err(ref e) if e.too_large && event_count > 1 => {
+                // Send each half as its own batch, in order, right away
+                let mut events = batch.events;
+                let second = events.split_off(event_count / 2);
+                guard.pending.push_front(Batch::new(second));
+                guard.pending.push_front(Batch::new(events));
+                if self.opts.debug {
+                    eprintln!("[peekapi] Batch too large, splitting {event_count} events");
                 }
-
+                true
+            }
+            Err(ref e) if !is_retryable(e) || e.too_large => {
+                drop(guard);
+                // Resending would fail the same way; keep the batch out of
+                // the storage file that is recovered into the buffer
+                let failed_at = now_iso8601();
+                self.dead_letter(
+                    batch
+                        .events
+                        .into_iter()
+                        .map(|event| DeadLetter {
+                            reason: DeadLetterReason::ClientError,
+                            detail: e.message.clone(),
+                            failed_at: failed_at.clone(),
+                            event,
+                        })
+                        .collect(),
+                );
                 if self.opts.debug {
-                    eprintln!("[peekapi] Flush failed ({event_count} events): {e}");
+                    eprintln!("[peekapi] Non-retryable error, dead-lettered: {e}");
                 }
                 self.call_on_error(e);
+                false
+            }
+            Err(ref e) => {
+                drop(guard);
+                self.retry_later(batch, e);
+                false
             }
         }
     }
 
+    /// Queue `batch` for a retry, ahead of newer events and under the same
+    /// ID, with exponential backoff; or persist it after too many
+    /// consecutive failures.
+    fn retry_later(&self, batch: Batch, err: &SendError) {
+        let event_count = batch.events.len();
+        let mut guard = self.inner.lock().unwrap();
+        guard.consecutive_failures += 1;
+        let failures = guard.consecutive_failures;
+
+        if failures >= MAX_CONSECUTIVE_FAILURES {
+            guard.consecutive_failures = 0;
+            drop(guard);
+            self.persist_to_disk(&batch);
+        } else {
+            guard.pending.push_front(batch);
+
+            // Exponential backoff with jitter
+            let base = BASE_BACKOFF * (1 << (failures - 1));
+            let jitter = 0.5 + rand_f64() * 0.5;
+            let delay = Duration::from_secs_f64(base.as_secs_f64() * jitter);
+            guard.backoff_until = Instant::now() + delay;
+            drop(guard);
+        }
+
+        if self.opts.debug {
+            eprintln!("[peekapi] Flush failed ({event_count} events): {err}");
+        }
+        self.call_on_error(err);
+    }
+
+    /// Count, report and dead-letter events the ingestion API refused
+    /// permanently.
+    fn report_rejected(&self, dropped: Vec<(RequestEvent, String)>) {
+        self.rejected_events
+            .fetch_add(dropped.len() as u64, Ordering::Relaxed);
+        let mut reasons: Vec<&str> = dropped.iter().map(|(_, reason)| reason.as_str()).collect();
+        reasons.sort_unstable();
+        reasons.dedup();
+        let err = SendError::new(
+            format!(
+                "Ingestion API rejected {} events: {}",
+                dropped.len(),
+                reasons.join("; ")
+            ),
+            false,
+        );
+        if self.opts.debug {
+            eprintln!("[peekapi] Dropped rejected events: {err}");
+        }
+        self.call_on_error(&err);
+
+        let failed_at = now_iso8601();
+        self.dead_letter(
+            dropped
+                .into_iter()
+                .map(|(event, detail)| DeadLetter {
+                    reason: DeadLetterReason::Rejected,
+                    detail,
+                    failed_at: failed_at.clone(),
+                    event,
+                })
+                .collect(),
+        );
+    }
+
     /// Graceful shutdown: stop background thread, final flush, persist remainder.
     pub fn shutdown(&self) {
         if self.closed.swap(true, Ordering::SeqCst) {
@@ -323,19 +596,128 @@ impl PeekApiClient {
         // Final flush
         self.flush();
 
-        // Persist any remaining events
-        let remaining = {
+        // Persist any remaining events, keeping failed batches whole
+        let (pending, remaining) = {
             let mut guard = self.inner.lock().unwrap();
-            std::mem::take(&mut guard.buffer)
+            (
+                std::mem::take(&mut guard.pending),
+                std::mem::take(&mut guard.buffer),
+            )
         };
+        for batch in pending {
+            self.persist_to_disk(&batch);
+        }
         if !remaining.is_empty() {
-            self.persist_to_disk(&remaining);
+            self.persist_to_disk(&Batch::new(remaining));
         }
     }
 
+    /// Replace the API key used for subsequent sends.
+    ///
+    /// Takes effect for the next request, including retries of batches
+    /// that failed with the old key. In-flight requests are not affected.
+    pub fn set_api_key(&self, api_key: impl Into<String>) -> Result<(), String> {
+        let api_key = api_key.into();
+        validate_api_key(&api_key)?;
+        *self.api_key.write().unwrap() = api_key;
+        Ok(())
+    }
+
     /// Returns whether query string collection is enabled.
     pub fn collect_query_string(&self) -> bool {
-        self.opts.collect_query_string
+        self.opts.query_string.is_some()
+    }
+
+    /// Returns the query string policy, if query collection is enabled.
+    pub fn query_string_policy(&self) -> Option<&QueryStringPolicy> {
+        self.opts.query_string.as_ref()
+    }
+
+    /// Build the tracked path from a request path and raw query string,
+    /// applying the query string policy. Shared by all middleware adapters.
+    pub fn tracked_path(&self, path: &str, query: Option<&str>) -> String {
+        let mut tracked = path.to_string();
+        if let (Some(policy), Some(qs)) = (&self.opts.query_string, query) {
+            let normalized = normalize_query(qs, policy);
+            if !normalized.is_empty() {
+                tracked.push('?');
+                tracked.push_str(&normalized);
+            }
+        }
+        tracked
+    }
+
+    /// Returns the header capture configuration, if set.
+    pub fn header_capture(&self) -> Option<&HeaderCapture> {
+        self.opts.capture_headers.as_ref()
+    }
+
+    /// Returns the body capture configuration, if set.
+    pub fn body_capture(&self) -> Option<&BodyCapture> {
+        self.opts.capture_body.as_ref()
+    }
+
+    /// Resolve the client IP for a request from the connection peer address
+    /// and forwarding headers. `get_header` should join repeated header
+    /// lines with `", "`. Returns `None` unless `client_ip` is configured.
+    pub fn client_ip<F>(&self, peer: Option<IpAddr>, get_header: F) -> Option<String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        self.opts.client_ip.as_ref()?.client_ip(peer, get_header)
+    }
+
+    /// Identify the consumer for a request, in order of precedence:
+    /// `identify_request`, `identify_consumer`, `consumer_chain`, then
+    /// default identification (a [`ConsumerIdentity`](crate::ConsumerIdentity)
+    /// set by the application, `x-api-key`, `Authorization`).
+    pub fn identify(&self, request: &dyn RequestView) -> Option<String> {
+        let opts = &self.opts;
+        if let Some(ref cb) = opts.identify_request {
+            return cb(request);
+        }
+        if let Some(ref cb) = opts.identify_consumer {
+            return cb(&|name| request.header(name));
+        }
+        if let Some(ref chain) = opts.consumer_chain {
+            return chain.identify(request, &opts.consumer_id_policy);
+        }
+        request
+            .consumer_identity()
+            .filter(|id| !id.is_empty())
+            .or_else(|| {
+                default_identify_consumer_with(
+                    |name| request.header(name),
+                    &opts.consumer_id_policy,
+                )
+            })
+    }
+
+    /// The legacy (unsalted) consumer ID for a request, when keyed hashing is
+    /// configured with `emit_legacy_hash` and it differs from `consumer_id`,
+    /// the ID [`identify`](Self::identify) returned for the request.
+    pub fn legacy_consumer_id(
+        &self,
+        request: &dyn RequestView,
+        consumer_id: Option<&str>,
+    ) -> Option<String> {
+        let opts = &self.opts;
+        if opts.identify_request.is_some()
+            || opts.identify_consumer.is_some()
+            || opts.consumer_chain.is_some()
+        {
+            return None;
+        }
+        if !opts
+            .consumer_id_policy
+            .hashing
+            .as_ref()
+            .is_some_and(|h| h.emit_legacy_hash)
+        {
+            return None;
+        }
+        let legacy = default_identify_consumer(|name| request.header(name))?;
+        (consumer_id != Some(legacy.as_str())).then_some(legacy)
     }
 
     /// Returns the custom consumer identification callback, if set.
@@ -343,9 +725,32 @@ impl PeekApiClient {
         &self.opts.identify_consumer
     }
 
-    /// Current number of events in the buffer (for testing).
+    /// Current number of buffered events, including batches waiting for a
+    /// retry (for testing).
     pub fn buffer_len(&self) -> usize {
-        self.inner.lock().unwrap().buffer.len()
+        self.inner.lock().unwrap().queued()
+    }
+
+    /// Number of events dropped because the ingestion API rejected them
+    /// permanently.
+    pub fn rejected_events(&self) -> u64 {
+        self.rejected_events.load(Ordering::Relaxed)
+    }
+
+    /// Events in the dead-letter store, oldest first.
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>, String> {
+        self.dead_letters.read()
+    }
+
+    /// Write the dead-letter store to `out` as JSONL, one [`DeadLetter`]
+    /// per line. Returns the number of records written.
+    pub fn export_dead_letters(&self, mut out: impl Write) -> Result<usize, String> {
+        self.dead_letters.export(&mut out)
+    }
+
+    /// Empty the dead-letter store. Returns the number of records removed.
+    pub fn purge_dead_letters(&self) -> Result<usize, String> {
+        self.dead_letters.purge()
     }
 
     /// Recover persisted events from disk into the buffer.
@@ -389,50 +794,198 @@ impl PeekApiClient {
     // Network
     // ------------------------------------------------------------------
 
-    fn send(&self, events: &[RequestEvent]) -> Result<(), SendError> {
-        let body = serde_json::to_vec(events)
-            .map_err(|e| SendError::new(format!("JSON marshal failed: {e}"), false))?;
-
-        let result = ureq::post(&self.opts.endpoint)
-            .timeout(SEND_TIMEOUT)
-            .set("Content-Type", "application/json")
-            .set("x-api-key", &self.opts.api_key)
-            .set(
-                "x-peekapi-sdk",
-                &format!("rust/{}", env!("CARGO_PKG_VERSION")),
-            )
-            .send_bytes(&body);
+    fn send(&self, batch: &Batch) -> Result<Delivery, SendError> {
+        let events = &batch.events;
+        let mut format = self.payload_format();
+        let mut body = format.encode(events).map_err(|e| SendError::new(e, true))?;
 
-        match result {
-            Ok(resp) => {
-                let status = resp.status();
-                if !(200..300).contains(&status) {
-                    let retryable = status == 429 || status >= 500;
+        let mut url = url::Url::parse(&self.opts.endpoint)
+            .map_err(|e| SendError::new(format!("Invalid endpoint: {e}"), true))?;
+        let mut send_credentials = true;
+        let mut refreshed = false;
+        let mut hops = 0;
+        loop {
+            if let Some(ref resolver) = self.opts.proxied_dns_check {
+                let (host, port) =
+                    endpoint_host_port(url.as_str()).map_err(|e| SendError::new(e, true))?;
+                resolve_and_validate(&host, port, &**resolver, &self.opts.endpoint_policy)
+                    .map_err(|e| SendError::new(e, true))?;
+            }
+            let mut request = self
+                .transport
+                .agent()
+                .post(url.as_str())
+                .set("Content-Type", format.content_type())
+                .set(
+                    "x-peekapi-sdk",
+                    &format!("rust/{}", env!("CARGO_PKG_VERSION")),
+                )
+                .set(IDEMPOTENCY_KEY_HEADER, &batch.id);
+            let api_key = self.api_key();
+            if send_credentials {
+                request = request.set("x-api-key", &api_key);
+                for (name, value) in &self.opts.headers {
+                    request = request.set(name, value);
+                }
+                if let Some(ref signing) = self.opts.signing {
+                    let (timestamp, signature) = signing.headers(&body);
+                    request = request
+                        .set(TIMESTAMP_HEADER, &timestamp)
+                        .set(SIGNATURE_HEADER, &signature);
+                }
+            }
+            if !self.transport.keep_alive() {
+                request = request.set("Connection", "close");
+            }
+            // Sent to the proxy itself; for HTTPS it goes in the CONNECT
+            // request and must not reach the endpoint inside the tunnel
+            if url.scheme() == "http" {
+                if let Some(auth) = self
+                    .opts
+                    .proxy
+                    .as_ref()
+                    .and_then(|p| p.authorization.as_ref())
+                {
+                    request = request.set("Proxy-Authorization", auth);
+                }
+            }
+
+            let resp = match request.send_bytes(&body) {
+                Ok(resp) => resp,
+                Err(ureq::Error::Status(401, _))
+                    if send_credentials && !refreshed && self.refresh_api_key(&api_key) =>
+                {
+                    // Retry once with the rotated key
+                    refreshed = true;
+                    continue;
+                }
+                Err(ureq::Error::Status(415, _)) if format != PayloadFormat::Json => {
+                    // The endpoint doesn't take this format: resend as JSON,
+                    // and keep using JSON for this client
+                    self.json_fallback.store(true, Ordering::Relaxed);
+                    if self.opts.debug {
+                        eprintln!(
+                            "[peekapi] Endpoint does not accept {}, falling back to JSON",
+                            format.content_type()
+                        );
+                    }
+                    format = PayloadFormat::Json;
+                    body = format.encode(events).map_err(|e| SendError::new(e, true))?;
+                    continue;
+                }
+                Err(ureq::Error::Status(413, _)) => {
+                    return Err(SendError::too_large());
+                }
+                Err(ureq::Error::Status(status, resp)) => {
+                    // A 4xx naming the bad events: drop those, resend the rest
+                    // (all of them, if every rejection is transient)
+                    if (400..500).contains(&status) && status != 429 {
+                        if let Some(rejected) = parse_rejections(&read_body(resp), events.len()) {
+                            if !rejected.is_empty() {
+                                return Ok(Delivery {
+                                    rejected,
+                                    stored: false,
+                                });
+                            }
+                        }
+                    }
+                    // Only a malformed batch is refused for good; auth,
+                    // routing and rate-limit errors are fixed server-side
+                    let permanent = matches!(status, 400 | 422);
                     return Err(SendError::new(
                         format!("Ingestion API returned {status}"),
-                        retryable,
+                        !permanent,
                     ));
                 }
-                Ok(())
+                Err(ureq::Error::Transport(e)) => {
+                    return Err(SendError::new(format!("Transport error: {e}"), true));
+                }
+            };
+
+            let status = resp.status();
+            if (200..300).contains(&status) {
+                let rejected = parse_rejections(&read_body(resp), events.len());
+                return Ok(match rejected {
+                    Some(rejected) => Delivery {
+                        rejected,
+                        stored: true,
+                    },
+                    None => Delivery::stored(),
+                });
             }
-            Err(ureq::Error::Status(status, _resp)) => {
-                let retryable = status == 429 || status >= 500;
-                Err(SendError::new(
+            // Only 307/308 preserve the POST body. Redirects not followed
+            // are kept for a retry, like an unreachable endpoint.
+            if !matches!(status, 307 | 308) || hops >= self.opts.max_redirects {
+                return Err(SendError::new(
                     format!("Ingestion API returned {status}"),
-                    retryable,
-                ))
+                    true,
+                ));
             }
-            Err(ureq::Error::Transport(e)) => {
-                Err(SendError::new(format!("Transport error: {e}"), true))
+
+            let next = self.redirect_target(&url, resp.header("location"))?;
+            if next.origin() != url.origin() {
+                // Never forward the API key, custom headers or signature
+                // to another origin
+                send_credentials = false;
             }
+            url = next;
+            hops += 1;
+        }
+    }
+
+    /// Resolve and validate a redirect `Location` against the current URL,
+    /// applying the same checks as the configured endpoint.
+    fn redirect_target(
+        &self,
+        current: &url::Url,
+        location: Option<&str>,
+    ) -> Result<url::Url, SendError> {
+        let refused = |reason: String| SendError::new(format!("Redirect refused: {reason}"), true);
+        let location = location.ok_or_else(|| refused("missing Location header".to_string()))?;
+        let next = current
+            .join(location)
+            .map_err(|e| refused(format!("{e}: {location}")))?;
+        validate_endpoint_with(next.as_str(), &self.opts.endpoint_policy).map_err(refused)?;
+        Ok(next)
+    }
+
+    fn payload_format(&self) -> PayloadFormat {
+        if self.json_fallback.load(Ordering::Relaxed) {
+            PayloadFormat::Json
+        } else {
+            self.opts.payload_format
         }
     }
 
+    fn api_key(&self) -> String {
+        self.api_key.read().unwrap().clone()
+    }
+
+    /// After a 401 for a request sent with `used`, ask the provider (if any)
+    /// for a fresh key. Returns whether a different key is now current,
+    /// either from the provider or a concurrent `set_api_key`.
+    fn refresh_api_key(&self, used: &str) -> bool {
+        if let Some(ref provider) = self.opts.api_key_provider {
+            match provider() {
+                Some(key) if validate_api_key(&key).is_ok() => {
+                    *self.api_key.write().unwrap() = key;
+                }
+                _ => {
+                    if self.opts.debug {
+                        eprintln!("[peekapi] API key provider returned no valid key");
+                    }
+                }
+            }
+        }
+        *self.api_key.read().unwrap() != used
+    }
+
     // ------------------------------------------------------------------
     // Disk persistence
     // ------------------------------------------------------------------
 
-    fn persist_to_disk(&self, events: &[RequestEvent]) {
+    fn persist_to_disk(&self, batch: &Batch) {
+        let events = &batch.events;
         if events.is_empty() {
             return;
         }
@@ -451,11 +1004,11 @@ impl PeekApiClient {
             return;
         }
 
-        let data = match serde_json::to_string(events) {
+        let data = match self.opts.storage_format.encode_record(batch) {
             Ok(d) => d,
             Err(e) => {
                 if self.opts.debug {
-                    eprintln!("[peekapi] Failed to marshal events for disk: {e}");
+                    eprintln!("[peekapi] Failed to encode events for disk: {e}");
                 }
                 return;
             }
@@ -468,7 +1021,7 @@ impl PeekApiClient {
 
         match file {
             Ok(mut f) => {
-                if let Err(e) = writeln!(f, "{data}") {
+                if let Err(e) = f.write_all(&data) {
                     if self.opts.debug {
                         eprintln!("[peekapi] Failed to write events to disk: {e}");
                     }
@@ -488,41 +1041,55 @@ impl PeekApiClient {
         }
     }
 
+    fn dead_letter(&self, letters: Vec<DeadLetter>) {
+        match self.dead_letters.append(&letters) {
+            Ok(written) if written < letters.len() => {
+                if self.opts.debug {
+                    eprintln!(
+                        "[peekapi] Dead-letter file full, discarded {} events",
+                        letters.len() - written
+                    );
+                }
+            }
+            Ok(written) => {
+                if self.opts.debug {
+                    eprintln!(
+                        "[peekapi] Dead-lettered {written} events to {}",
+                        self.dead_letters.path()
+                    );
+                }
+            }
+            Err(e) => {
+                if self.opts.debug {
+                    eprintln!("{e}");
+                }
+            }
+        }
+    }
+
     fn load_from_disk(&self) {
-        let file = match fs::File::open(&self.opts.storage_path) {
-            Ok(f) => f,
+        let data = match fs::read(&self.opts.storage_path) {
+            Ok(data) => data,
             Err(_) => return, // file doesn't exist
         };
 
-        let reader = BufReader::new(file);
         let mut loaded = 0usize;
         let mut guard = self.inner.lock().unwrap();
 
-        for line in reader.lines() {
-            let line = match line {
-                Ok(l) => l,
-                Err(_) => continue,
-            };
-            let line = line.trim().to_string();
-            if line.is_empty() {
-                continue;
+        // Any storage format is read back, so changing it loses nothing.
+        // Batches keep their IDs, so a resend can be deduplicated.
+        for mut batch in decode_records(&data) {
+            if guard.queued() >= self.opts.max_buffer_size {
+                break;
             }
-
-            let batch: Vec<RequestEvent> = match serde_json::from_str(&line) {
-                Ok(b) => b,
-                Err(_) => continue, // skip corrupt lines
-            };
-
-            for event in batch {
-                if guard.buffer.len() >= self.opts.max_buffer_size {
-                    break;
+            // Files written before event IDs existed
+            for event in &mut batch.events {
+                if event.event_id.is_empty() {
+                    event.event_id = new_id();
                 }
-                guard.buffer.push(event);
-                loaded += 1;
-            }
-            if guard.buffer.len() >= self.opts.max_buffer_size {
-                break;
             }
+            loaded += batch.events.len();
+            guard.pending.push_back(batch);
         }
 
         drop(guard);
@@ -554,6 +1121,52 @@ impl Drop for PeekApiClient {
     }
 }
 
+// ------------------------------------------------------------------
+// Option validation
+// ------------------------------------------------------------------
+
+fn validate_api_key(api_key: &str) -> Result<(), String> {
+    if api_key.is_empty() {
+        return Err("[peekapi] 'api_key' is required".to_string());
+    }
+    if api_key.contains('\0') || api_key.contains('\r') || api_key.contains('\n') {
+        return Err("[peekapi] 'api_key' contains invalid characters".to_string());
+    }
+    Ok(())
+}
+
+/// Validate `Options::headers`: names must be HTTP tokens and not reserved,
+/// values visible ASCII, spaces and tabs (as ureq requires).
+fn custom_headers(headers: BTreeMap<String, String>) -> Result<Vec<(String, String)>, String> {
+    let mut seen = Vec::with_capacity(headers.len());
+    for (name, value) in headers {
+        let lower = name.to_ascii_lowercase();
+        let is_token = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !is_token {
+            return Err(format!("[peekapi] Invalid header name: {name:?}"));
+        }
+        if RESERVED_HEADERS.contains(&lower.as_str()) || lower.starts_with("x-peekapi-") {
+            return Err(format!("[peekapi] Header '{name}' is reserved"));
+        }
+        if !is_valid_header_value(&value) {
+            return Err(format!(
+                "[peekapi] Header '{name}' contains invalid characters"
+            ));
+        }
+        if seen
+            .iter()
+            .any(|(n, _): &(String, String)| n.eq_ignore_ascii_case(&name))
+        {
+            return Err(format!("[peekapi] Duplicate header '{name}'"));
+        }
+        seen.push((name, value));
+    }
+    Ok(seen)
+}
+
 // ------------------------------------------------------------------
 // Error types
 // ------------------------------------------------------------------
@@ -561,12 +1174,28 @@ impl Drop for PeekApiClient {
 #[derive(Debug)]
 struct SendError {
     message: String,
+    /// False only for a batch the API refused as a whole (400/422), which
+    /// is dead-lettered.
     retryable: bool,
+    /// The batch got a 413 and is resent in halves.
+    too_large: bool,
 }
 
 impl SendError {
     fn new(message: String, retryable: bool) -> Self {
-        Self { message, retryable }
+        Self {
+            message,
+            retryable,
+            too_large: false,
+        }
+    }
+
+    fn too_large() -> Self {
+        Self {
+            message: "Ingestion API returned 413".to_string(),
+            retryable: true,
+            too_large: true,
+        }
     }
 }
 
@@ -578,10 +1207,30 @@ impl std::fmt::Display for SendError {
 
 impl std::error::Error for SendError {}
 
+/// Default dead-letter file next to the storage file:
+/// `events.jsonl` becomes `events.dead-letter.jsonl`.
+fn dead_letter_path(storage_path: &str) -> String {
+    match storage_path.strip_suffix(".jsonl") {
+        Some(stem) => format!("{stem}.dead-letter.jsonl"),
+        None => format!("{storage_path}.dead-letter"),
+    }
+}
+
 fn is_retryable(err: &SendError) -> bool {
     err.retryable
 }
 
+/// Read a response body, up to `MAX_RESPONSE_BYTES`. Read errors leave it
+/// truncated, which only loses per-event results.
+fn read_body(resp: ureq::Response) -> Vec<u8> {
+    let mut body = Vec::new();
+    let _ = resp
+        .into_reader()
+        .take(MAX_RESPONSE_BYTES)
+        .read_to_end(&mut body);
+    body
+}
+
 // ------------------------------------------------------------------
 // Utilities
 // ------------------------------------------------------------------
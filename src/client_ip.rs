@@ -0,0 +1,333 @@
+use crate::consumer::hex;
+use crate::ssrf::{parse_ip_lenient, Cidr};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// How the client IP is anonymized before it is stored.
+#[derive(Clone, Default)]
+pub enum IpAnonymization {
+    /// Store the full address.
+    None,
+    /// Zero the host part: IPv4 to /24, IPv6 to /48.
+    #[default]
+    Truncate,
+    /// Store an HMAC-SHA256 of the address keyed with a secret
+    /// (`hash_<16 hex chars>`). Stable per key, not reversible without it.
+    KeyedHash(Vec<u8>),
+    /// Do not store the client IP.
+    Omit,
+}
+
+impl std::fmt::Debug for IpAnonymization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::Truncate => write!(f, "Truncate"),
+            Self::KeyedHash(_) => write!(f, "KeyedHash(..)"),
+            Self::Omit => write!(f, "Omit"),
+        }
+    }
+}
+
+/// The forwarding header written by the trusted proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardingHeader {
+    /// `X-Forwarded-For: client, proxy1, proxy2`
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=client, for=proxy1`
+    Forwarded,
+    /// `X-Real-IP: client`, set (not appended) by the proxy.
+    XRealIp,
+}
+
+impl ForwardingHeader {
+    fn name(self) -> &'static str {
+        match self {
+            Self::XForwardedFor => "x-forwarded-for",
+            Self::Forwarded => "forwarded",
+            Self::XRealIp => "x-real-ip",
+        }
+    }
+}
+
+/// Client IP capture settings.
+///
+/// The peer address of the connection is used unless it belongs to a trusted
+/// proxy, in which case the configured forwarding header is consulted. Only
+/// that header is read: any other can be sent by the client and passed
+/// through unchanged by the proxy. Forwarding chains are walked right to
+/// left, skipping trusted proxies, so a client cannot spoof its address by
+/// prepending entries.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpOptions {
+    /// Proxies whose forwarding headers are trusted, in CIDR notation
+    /// (`10.0.0.0/8`, `2001:db8::/32`) or as bare addresses.
+    pub trusted_proxies: Vec<String>,
+    /// Header the trusted proxies append the client address to. Default:
+    /// `X-Forwarded-For`.
+    pub forwarding_header: ForwardingHeader,
+    /// Anonymization applied before storing. Default: truncate.
+    pub anonymization: IpAnonymization,
+}
+
+/// Validated client IP configuration.
+pub(crate) struct ClientIpConfig {
+    trusted: Vec<Cidr>,
+    header: ForwardingHeader,
+    anonymization: IpAnonymization,
+}
+
+impl ClientIpConfig {
+    pub(crate) fn new(opts: ClientIpOptions) -> Result<Self, String> {
+        let trusted = opts
+            .trusted_proxies
+            .iter()
+            .map(|p| Cidr::parse(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            trusted,
+            header: opts.forwarding_header,
+            anonymization: opts.anonymization,
+        })
+    }
+
+    /// Resolve and anonymize the client IP for a request.
+    pub(crate) fn client_ip<F>(&self, peer: Option<IpAddr>, get_header: F) -> Option<String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if matches!(self.anonymization, IpAnonymization::Omit) {
+            return None;
+        }
+        let ip = resolve_client_ip(
+            peer?,
+            get_header(self.header.name()),
+            self.header,
+            &self.trusted,
+        );
+        Some(anonymize_ip(ip, &self.anonymization))
+    }
+}
+
+/// Join the lines of a repeated header into one comma-separated value, as
+/// proxies may add their own line instead of appending to the client's.
+#[cfg_attr(
+    not(any(
+        feature = "actix",
+        feature = "axum-middleware",
+        feature = "rocket-fairing"
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn join_header_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Option<String> {
+    let joined = lines.collect::<Vec<_>>().join(", ");
+    (!joined.is_empty()).then_some(joined)
+}
+
+fn resolve_client_ip(
+    peer: IpAddr,
+    value: Option<String>,
+    header: ForwardingHeader,
+    trusted: &[Cidr],
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|c| c.contains(*ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let value = value.unwrap_or_default();
+    let chain: Vec<IpAddr> = match header {
+        ForwardingHeader::Forwarded => value
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for")
+                        .then(|| parse_ip_lenient(value.trim_matches('"')))
+                        .flatten()
+                })
+            })
+            .collect(),
+        ForwardingHeader::XForwardedFor => value.split(',').filter_map(parse_ip_lenient).collect(),
+        ForwardingHeader::XRealIp => parse_ip_lenient(&value).into_iter().collect(),
+    };
+
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or(chain.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+fn anonymize_ip(ip: IpAddr, mode: &IpAnonymization) -> String {
+    match mode {
+        IpAnonymization::None | IpAnonymization::Omit => ip.to_string(),
+        IpAnonymization::Truncate => match ip {
+            IpAddr::V4(v4) => Ipv4Addr::from(u32::from(v4) & 0xffff_ff00).to_string(),
+            IpAddr::V6(v6) => {
+                if let Some(v4) = v6.to_ipv4_mapped() {
+                    return anonymize_ip(IpAddr::V4(v4), mode);
+                }
+                let mask = !0u128 << 80;
+                Ipv6Addr::from(u128::from(v6) & mask).to_string()
+            }
+        },
+        IpAnonymization::KeyedHash(key) => {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+            mac.update(ip.to_string().as_bytes());
+            let digest = mac.finalize().into_bytes();
+            format!("hash_{}", hex::encode(&digest[..8]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted: &[&str], anonymization: IpAnonymization) -> ClientIpConfig {
+        config_with(trusted, ForwardingHeader::default(), anonymization)
+    }
+
+    fn config_with(
+        trusted: &[&str],
+        forwarding_header: ForwardingHeader,
+        anonymization: IpAnonymization,
+    ) -> ClientIpConfig {
+        ClientIpConfig::new(ClientIpOptions {
+            trusted_proxies: trusted.iter().map(|s| s.to_string()).collect(),
+            forwarding_header,
+            anonymization,
+        })
+        .unwrap()
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_forwarding_headers() {
+        let cfg = config(&["10.0.0.0/8"], IpAnonymization::None);
+        let got = cfg.client_ip(ip("203.0.113.9"), |name| {
+            (name == "x-forwarded-for").then(|| "1.2.3.4".to_string())
+        });
+        assert_eq!(got.as_deref(), Some("203.0.113.9"));
+    }
+
+    #[test]
+    fn trusted_peer_walks_forwarded_for_from_the_right() {
+        let cfg = config(&["10.0.0.0/8"], IpAnonymization::None);
+        // Client spoofed 6.6.6.6; 198.51.100.7 connected to our proxy 10.0.0.2
+        let got = cfg.client_ip(ip("10.0.0.1"), |name| {
+            (name == "x-forwarded-for").then(|| "6.6.6.6, 198.51.100.7, 10.0.0.2".to_string())
+        });
+        assert_eq!(got.as_deref(), Some("198.51.100.7"));
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        // The proxy appended to X-Forwarded-For and passed the client's own
+        // Forwarded header through
+        let headers = |name: &str| match name {
+            "forwarded" => Some("for=1.2.3.4".to_string()),
+            "x-forwarded-for" => Some("198.51.100.7".to_string()),
+            _ => None,
+        };
+        let cfg = config(&["10.0.0.1"], IpAnonymization::None);
+        assert_eq!(
+            cfg.client_ip(ip("10.0.0.1"), headers).as_deref(),
+            Some("198.51.100.7")
+        );
+        let cfg = config_with(
+            &["10.0.0.1"],
+            ForwardingHeader::Forwarded,
+            IpAnonymization::None,
+        );
+        assert_eq!(
+            cfg.client_ip(ip("10.0.0.1"), headers).as_deref(),
+            Some("1.2.3.4")
+        );
+    }
+
+    #[test]
+    fn forwarded_header_elements() {
+        let cfg = config_with(
+            &["10.0.0.1"],
+            ForwardingHeader::Forwarded,
+            IpAnonymization::None,
+        );
+        let got = cfg.client_ip(ip("10.0.0.1"), |name| {
+            (name == "forwarded")
+                .then(|| r#"for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.1"#.to_string())
+        });
+        assert_eq!(got.as_deref(), Some("2001:db8:cafe::17"));
+    }
+
+    #[test]
+    fn repeated_header_lines_are_joined() {
+        // The client sent its own line; the proxy added a second one
+        let joined = join_header_lines(["6.6.6.6", "198.51.100.7"].into_iter());
+        assert_eq!(joined.as_deref(), Some("6.6.6.6, 198.51.100.7"));
+        assert_eq!(join_header_lines(std::iter::empty()), None);
+
+        let cfg = config(&["10.0.0.0/8"], IpAnonymization::None);
+        let got = cfg.client_ip(ip("10.0.0.1"), |_| joined.clone());
+        assert_eq!(got.as_deref(), Some("198.51.100.7"));
+    }
+
+    #[test]
+    fn x_real_ip_and_missing_headers() {
+        let cfg = config_with(
+            &["10.0.0.0/8"],
+            ForwardingHeader::XRealIp,
+            IpAnonymization::None,
+        );
+        let got = cfg.client_ip(ip("10.0.0.1"), |name| {
+            (name == "x-real-ip").then(|| "198.51.100.7".to_string())
+        });
+        assert_eq!(got.as_deref(), Some("198.51.100.7"));
+        assert_eq!(
+            cfg.client_ip(ip("10.0.0.1"), |_| None).as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(cfg.client_ip(None, |_| None), None);
+    }
+
+    #[test]
+    fn anonymization_modes() {
+        let v4 = ip("198.51.100.77").unwrap();
+        let v6 = ip("2001:db8:cafe:1:2:3:4:5").unwrap();
+        assert_eq!(anonymize_ip(v4, &IpAnonymization::Truncate), "198.51.100.0");
+        assert_eq!(
+            anonymize_ip(v6, &IpAnonymization::Truncate),
+            "2001:db8:cafe::"
+        );
+
+        let hashed = anonymize_ip(v4, &IpAnonymization::KeyedHash(b"k1".to_vec()));
+        assert!(hashed.starts_with("hash_"));
+        assert_eq!(hashed.len(), 5 + 16);
+        assert_ne!(
+            hashed,
+            anonymize_ip(v4, &IpAnonymization::KeyedHash(b"k2".to_vec()))
+        );
+
+        let omit = config(&[], IpAnonymization::Omit);
+        assert_eq!(omit.client_ip(Some(v4), |_| None), None);
+    }
+
+    #[test]
+    fn invalid_trusted_proxy_is_rejected() {
+        assert!(ClientIpConfig::new(ClientIpOptions {
+            trusted_proxies: vec!["10.0.0.0/99".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -1,4 +1,15 @@
-use sha2::{Digest, Sha256};
+use crate::jwt::JwtPublicKey;
+use crate::request::RequestView;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::sync::Arc;
+
+const DEFAULT_KEYED_HASH_LENGTH: usize = 32;
+const MIN_KEYED_HASH_LENGTH: usize = 16;
+const MAX_KEYED_HASH_LENGTH: usize = 64;
 
 /// SHA-256 hash truncated to 12 hex chars, prefixed with `hash_`.
 pub fn hash_consumer_id(raw: &str) -> String {
@@ -6,29 +17,517 @@ pub fn hash_consumer_id(raw: &str) -> String {
     format!("hash_{}", hex::encode(&hash[..6]))
 }
 
+/// HMAC-SHA256 keyed with `secret`, truncated to `hex_len` hex chars
+/// (clamped to 16..=64), prefixed with `hmac_`.
+///
+/// Unlike [`hash_consumer_id`], the output cannot be brute-forced or
+/// confirmed for a known credential without the secret.
+pub fn hash_consumer_id_keyed(raw: &str, secret: &[u8], hex_len: usize) -> String {
+    let hex_len = hex_len.clamp(MIN_KEYED_HASH_LENGTH, MAX_KEYED_HASH_LENGTH);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(raw.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let mut out = hex::encode(&digest);
+    out.truncate(hex_len);
+    format!("hmac_{out}")
+}
+
+/// Keyed hashing for credentials used as consumer IDs.
+#[derive(Clone)]
+pub struct ConsumerHashing {
+    /// HMAC secret. Keep it stable — changing it changes every consumer ID.
+    pub secret: Vec<u8>,
+    /// Output length in hex chars (16..=64). Default: 32 (128 bits).
+    pub length: usize,
+    /// Also emit the legacy unsalted hash as `metadata.consumer_id_legacy`,
+    /// so consumers tracked before the switch can be correlated with their
+    /// new IDs. Intended for a transition period only.
+    pub emit_legacy_hash: bool,
+}
+
+impl ConsumerHashing {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+            length: DEFAULT_KEYED_HASH_LENGTH,
+            emit_legacy_hash: false,
+        }
+    }
+
+    /// Hash a raw credential.
+    pub fn hash(&self, raw: &str) -> String {
+        hash_consumer_id_keyed(raw, &self.secret, self.length)
+    }
+}
+
+impl std::fmt::Debug for ConsumerHashing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConsumerHashing")
+            .field("secret", &"..")
+            .field("length", &self.length)
+            .field("emit_legacy_hash", &self.emit_legacy_hash)
+            .finish()
+    }
+}
+
+/// Maps a raw API key to a customer ID. Return `None` for unknown keys.
+pub type ApiKeyLookupFn = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// How `x-api-key` values become consumer IDs.
+///
+/// API keys are live customer secrets: storing them raw ships them to the
+/// analytics backend and writes them to the local storage file.
+#[derive(Clone, Default)]
+pub enum ApiKeyPolicy {
+    /// Store the key as-is. Not recommended.
+    Raw,
+    /// Hash the key (keyed if `consumer_hashing` is configured). Default.
+    #[default]
+    Hash,
+    /// Keep only the first N characters (never more than half the key),
+    /// e.g. `ak_live_1234…`.
+    Prefix(usize),
+    /// Resolve the key to a customer ID via a user-provided mapping,
+    /// hashing keys the mapping does not know.
+    Lookup(ApiKeyLookupFn),
+}
+
+impl std::fmt::Debug for ApiKeyPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Raw => write!(f, "Raw"),
+            Self::Hash => write!(f, "Hash"),
+            Self::Prefix(n) => write!(f, "Prefix({n})"),
+            Self::Lookup(_) => write!(f, "Lookup(..)"),
+        }
+    }
+}
+
+/// Identifies consumers by a claim in a bearer JWT (`Authorization: Bearer <jwt>`).
+///
+/// The payload is decoded locally — no network access. Without a
+/// verification key the signature is not checked, which is fine for
+/// analytics but means a client can choose its own claim value; configure
+/// `verify_hmac` for HMAC-signed tokens and `verify_public_key` for
+/// RSA/ECDSA-signed ones. Tokens that fail to parse or verify fall back to
+/// the hashed `Authorization` header; [`verify_token`](Self::verify_token)
+/// says why.
+#[derive(Clone)]
+pub struct JwtIdentifier {
+    /// Claim used as the consumer ID, e.g. `sub`, `client_id` or `azp`.
+    pub claim: String,
+    /// Secret for verifying HS256/HS384/HS512 signatures.
+    pub hmac_secret: Option<Vec<u8>>,
+    /// Key for verifying RS*, PS*, ES256 and ES384 signatures.
+    pub public_key: Option<JwtPublicKey>,
+}
+
+impl JwtIdentifier {
+    pub fn new(claim: impl Into<String>) -> Self {
+        Self {
+            claim: claim.into(),
+            hmac_secret: None,
+            public_key: None,
+        }
+    }
+
+    /// Require a valid HMAC signature made with `secret`.
+    pub fn verify_hmac(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.hmac_secret = Some(secret.into());
+        self
+    }
+
+    /// Require a valid RSA or ECDSA signature made with the private half of
+    /// `key`.
+    pub fn verify_public_key(mut self, key: JwtPublicKey) -> Self {
+        self.public_key = Some(key);
+        self
+    }
+
+    /// Extract the configured claim from a compact JWT.
+    ///
+    /// Returns `None` if the token is malformed, the signature does not
+    /// verify (when a key is configured), or the claim is missing. String and
+    /// numeric claims are supported.
+    pub fn claim_from_token(&self, token: &str) -> Option<String> {
+        self.verify_token(token).ok()
+    }
+
+    /// Same as [`claim_from_token`](Self::claim_from_token), with the reason
+    /// a token was not used, e.g. an algorithm the configured keys cannot
+    /// verify.
+    pub fn verify_token(&self, token: &str) -> Result<String, String> {
+        let malformed = || "[peekapi] Malformed JWT".to_string();
+        let mut parts = token.split('.');
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(h), Some(p), Some(s)) if parts.next().is_none() => (h, p, s),
+            _ => return Err(malformed()),
+        };
+
+        if self.hmac_secret.is_some() || self.public_key.is_some() {
+            let header: serde_json::Value = URL_SAFE_NO_PAD
+                .decode(header)
+                .ok()
+                .and_then(|h| serde_json::from_slice(&h).ok())
+                .ok_or_else(malformed)?;
+            let alg = header
+                .get("alg")
+                .and_then(|a| a.as_str())
+                .ok_or_else(malformed)?;
+            let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| malformed())?;
+            let (signed, _) = token.rsplit_once('.').ok_or_else(malformed)?;
+            self.verify_signature(alg, signed, &signature)?;
+        }
+
+        let claims: serde_json::Value = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|p| serde_json::from_slice(&p).ok())
+            .ok_or_else(malformed)?;
+        match claims.get(&self.claim) {
+            Some(serde_json::Value::String(s)) if !s.is_empty() => Ok(s.clone()),
+            Some(serde_json::Value::Number(n)) => Ok(n.to_string()),
+            _ => Err(format!("[peekapi] JWT has no \"{}\" claim", self.claim)),
+        }
+    }
+
+    fn verify_signature(&self, alg: &str, signed: &str, signature: &[u8]) -> Result<(), String> {
+        let unsupported = || format!("[peekapi] Unsupported JWT algorithm: {alg}");
+        if alg.starts_with("HS") {
+            let secret = self.hmac_secret.as_ref().ok_or_else(|| {
+                format!("[peekapi] JWT algorithm {alg} needs an HMAC secret (verify_hmac)")
+            })?;
+            let valid = match alg {
+                "HS256" => verify_hmac::<Hmac<Sha256>>(secret, signed, signature),
+                "HS384" => verify_hmac::<Hmac<Sha384>>(secret, signed, signature),
+                "HS512" => verify_hmac::<Hmac<Sha512>>(secret, signed, signature),
+                _ => return Err(unsupported()),
+            };
+            return if valid {
+                Ok(())
+            } else {
+                Err("[peekapi] JWT signature does not verify".to_string())
+            };
+        }
+        match alg {
+            "RS256" | "RS384" | "RS512" | "PS256" | "PS384" | "PS512" | "ES256" | "ES384" => {
+                let key = self.public_key.as_ref().ok_or_else(|| {
+                    format!("[peekapi] JWT algorithm {alg} needs a public key (verify_public_key)")
+                })?;
+                key.verify(alg, signed.as_bytes(), signature)
+            }
+            _ => Err(unsupported()),
+        }
+    }
+}
+
+impl std::fmt::Debug for JwtIdentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtIdentifier")
+            .field("claim", &self.claim)
+            .field("hmac_secret", &self.hmac_secret.as_ref().map(|_| ".."))
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+fn verify_hmac<M: Mac + hmac::digest::KeyInit>(
+    secret: &[u8],
+    signed: &str,
+    signature: &[u8],
+) -> bool {
+    let Ok(mut mac) = <M as Mac>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(signed.as_bytes());
+    mac.verify_slice(signature).is_ok()
+}
+
+/// Settings for [`default_identify_consumer_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerIdPolicy {
+    /// How `x-api-key` values are stored.
+    pub api_key: ApiKeyPolicy,
+    /// Keyed hashing for hashed credentials. Default: unsalted SHA-256.
+    pub hashing: Option<ConsumerHashing>,
+    /// Use a JWT claim instead of hashing bearer tokens.
+    pub jwt: Option<JwtIdentifier>,
+}
+
+impl ConsumerIdPolicy {
+    pub(crate) fn hash(&self, raw: &str) -> String {
+        match self.hashing {
+            Some(ref h) => h.hash(raw),
+            None => hash_consumer_id(raw),
+        }
+    }
+
+    fn api_key_id(&self, key: String) -> String {
+        match self.api_key {
+            ApiKeyPolicy::Raw => key,
+            ApiKeyPolicy::Hash => self.hash(&key),
+            ApiKeyPolicy::Prefix(n) => {
+                let keep = n.min(key.chars().count() / 2);
+                let prefix: String = key.chars().take(keep).collect();
+                format!("{prefix}…")
+            }
+            ApiKeyPolicy::Lookup(ref lookup) => lookup(&key).unwrap_or_else(|| self.hash(&key)),
+        }
+    }
+}
+
+/// Whether a value found by a [`ConsumerChain`] step is stored as-is or hashed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdEncoding {
+    /// Store the value verbatim. Only for identifiers that are not secrets.
+    Raw,
+    /// Hash the value (keyed if `consumer_hashing` is configured).
+    #[default]
+    Hash,
+}
+
+/// Where a [`ConsumerChain`] step reads the consumer ID from.
+#[derive(Debug, Clone)]
+pub enum IdSource {
+    /// A request header.
+    Header(String),
+    /// A cookie from the `Cookie` header.
+    Cookie(String),
+    /// A query parameter, as sent (not percent-decoded).
+    Query(String),
+    /// A path segment by zero-based index, e.g. `1` for `/tenants/{id}/...`.
+    PathSegment(usize),
+    /// The user name of `Authorization: Basic` credentials.
+    BasicAuthUsername,
+    /// A claim of an `Authorization: Bearer` JWT.
+    JwtClaim(JwtIdentifier),
+    /// A client certificate identity forwarded by the TLS terminator, e.g.
+    /// `x-ssl-client-s-dn` (nginx) or `x-forwarded-client-cert` (Envoy, whose
+    /// `Subject` or `URI` field is used).
+    ClientCertificate(String),
+    /// [`PeerIdentity`](crate::PeerIdentity) set by an in-process TLS acceptor.
+    PeerIdentity,
+    /// [`ConsumerIdentity`](crate::ConsumerIdentity) set by the application's
+    /// auth layer.
+    Identity,
+    /// `x-api-key`, stored per the `api_key_policy` option.
+    ApiKey,
+    /// The whole `Authorization` header.
+    Authorization,
+}
+
+/// Ordered list of consumer identification strategies; the first step that
+/// yields a non-empty value wins.
+///
+/// ```
+/// use peekapi::{ConsumerChain, IdEncoding, JwtIdentifier};
+///
+/// let chain = ConsumerChain::new()
+///     .header("x-client-id", IdEncoding::Raw)
+///     .jwt_claim(JwtIdentifier::new("sub"))
+///     .authorization();
+/// ```
+///
+/// Header-based sources (including client certificate headers) must only be
+/// trusted when a proxy in front of the application sets or strips them.
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerChain {
+    pub steps: Vec<(IdSource, IdEncoding)>,
+}
+
+impl ConsumerChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a step.
+    pub fn step(mut self, source: IdSource, encoding: IdEncoding) -> Self {
+        self.steps.push((source, encoding));
+        self
+    }
+
+    pub fn header(self, name: &str, encoding: IdEncoding) -> Self {
+        self.step(IdSource::Header(name.to_ascii_lowercase()), encoding)
+    }
+
+    pub fn cookie(self, name: impl Into<String>, encoding: IdEncoding) -> Self {
+        self.step(IdSource::Cookie(name.into()), encoding)
+    }
+
+    pub fn query_param(self, name: impl Into<String>, encoding: IdEncoding) -> Self {
+        self.step(IdSource::Query(name.into()), encoding)
+    }
+
+    pub fn path_segment(self, index: usize, encoding: IdEncoding) -> Self {
+        self.step(IdSource::PathSegment(index), encoding)
+    }
+
+    pub fn basic_auth_username(self, encoding: IdEncoding) -> Self {
+        self.step(IdSource::BasicAuthUsername, encoding)
+    }
+
+    /// JWT claims are stored raw; a token without the claim falls through to
+    /// the next step.
+    pub fn jwt_claim(self, jwt: JwtIdentifier) -> Self {
+        self.step(IdSource::JwtClaim(jwt), IdEncoding::Raw)
+    }
+
+    pub fn client_certificate(self, header: &str, encoding: IdEncoding) -> Self {
+        self.step(
+            IdSource::ClientCertificate(header.to_ascii_lowercase()),
+            encoding,
+        )
+    }
+
+    pub fn peer_identity(self, encoding: IdEncoding) -> Self {
+        self.step(IdSource::PeerIdentity, encoding)
+    }
+
+    /// The [`ConsumerIdentity`](crate::ConsumerIdentity) set by the
+    /// application, stored raw.
+    pub fn identity(self) -> Self {
+        self.step(IdSource::Identity, IdEncoding::Raw)
+    }
+
+    /// `x-api-key`, encoded per the `api_key_policy` option.
+    pub fn api_key(self) -> Self {
+        self.step(IdSource::ApiKey, IdEncoding::Hash)
+    }
+
+    /// The hashed `Authorization` header.
+    pub fn authorization(self) -> Self {
+        self.step(IdSource::Authorization, IdEncoding::Hash)
+    }
+
+    /// Run the chain against a request.
+    pub fn identify(&self, request: &dyn RequestView, policy: &ConsumerIdPolicy) -> Option<String> {
+        self.steps.iter().find_map(|(source, encoding)| {
+            let value = match source {
+                IdSource::Header(name) => request.header(name),
+                IdSource::Cookie(name) => request.cookie(name),
+                IdSource::Query(name) => request.query().and_then(|q| query_value(q, name)),
+                IdSource::PathSegment(index) => request
+                    .path()
+                    .split('/')
+                    .filter(|s| !s.is_empty())
+                    .nth(*index)
+                    .map(str::to_string),
+                IdSource::BasicAuthUsername => request
+                    .header("authorization")
+                    .and_then(|auth| basic_auth_username(&auth)),
+                IdSource::JwtClaim(jwt) => request
+                    .header("authorization")
+                    .and_then(|auth| bearer_claim(jwt, &auth)),
+                IdSource::ClientCertificate(name) => {
+                    request.header(name).and_then(|v| client_cert_identity(&v))
+                }
+                IdSource::PeerIdentity => request.peer_identity(),
+                IdSource::Identity => request.consumer_identity(),
+                IdSource::ApiKey => {
+                    let key = request.header("x-api-key").filter(|k| !k.is_empty())?;
+                    return Some(policy.api_key_id(key));
+                }
+                IdSource::Authorization => request.header("authorization"),
+            };
+            let value = value.filter(|v| !v.is_empty())?;
+            Some(match encoding {
+                IdEncoding::Raw => value,
+                IdEncoding::Hash => policy.hash(&value),
+            })
+        })
+    }
+}
+
+fn query_value(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        (k == name).then(|| v.to_string())
+    })
+}
+
+fn basic_auth_username(auth: &str) -> Option<String> {
+    let (scheme, credentials) = auth.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, _password) = decoded.split_once(':')?;
+    Some(user.to_string())
+}
+
+fn bearer_claim(jwt: &JwtIdentifier, auth: &str) -> Option<String> {
+    let (scheme, token) = auth.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    jwt.claim_from_token(token.trim())
+}
+
+/// Identity from a forwarded client certificate header. Envoy's
+/// `x-forwarded-client-cert` (`By=…;Hash=…;Subject="…";URI=…`) yields the
+/// `Subject` (or `URI`) of the first element; other values are used as-is.
+fn client_cert_identity(value: &str) -> Option<String> {
+    let mut in_quotes = false;
+    let end = value
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ',' && !in_quotes
+        })
+        .map_or(value.len(), |(i, _)| i);
+    let first = value[..end].trim();
+    let field = |name: &str| {
+        first.split(';').find_map(|pair| {
+            let (k, v) = pair.trim().split_once('=')?;
+            k.eq_ignore_ascii_case(name)
+                .then(|| v.trim_matches('"').to_string())
+        })
+    };
+    if first.contains("Hash=") || first.contains("By=") {
+        return field("Subject")
+            .filter(|s| !s.is_empty())
+            .or_else(|| field("URI"));
+    }
+    Some(value.trim().to_string())
+}
+
 /// Identify consumer from request headers.
 ///
 /// Priority:
-///   1. `x-api-key` (stored as-is)
+///   1. `x-api-key` (hashed — API keys are secrets)
 ///   2. `Authorization` (hashed — contains credentials)
 pub fn default_identify_consumer<F>(get_header: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    default_identify_consumer_with(get_header, &ConsumerIdPolicy::default())
+}
+
+/// Same as [`default_identify_consumer`], storing API keys per
+/// `policy.api_key`, reading bearer JWT claims per `policy.jwt`, and hashing
+/// credentials with `policy.hashing`.
+pub fn default_identify_consumer_with<F>(get_header: F, policy: &ConsumerIdPolicy) -> Option<String>
 where
     F: Fn(&str) -> Option<String>,
 {
     if let Some(key) = get_header("x-api-key") {
         if !key.is_empty() {
-            return Some(key);
+            return Some(policy.api_key_id(key));
         }
     }
     if let Some(auth) = get_header("authorization") {
         if !auth.is_empty() {
-            return Some(hash_consumer_id(&auth));
+            let claim = policy.jwt.as_ref().and_then(|jwt| bearer_claim(jwt, &auth));
+            return Some(claim.unwrap_or_else(|| policy.hash(&auth)));
         }
     }
     None
 }
 
-mod hex {
+pub(crate) mod hex {
     /// Encode bytes as lowercase hex string.
     pub fn encode(bytes: &[u8]) -> String {
         bytes.iter().map(|b| format!("{b:02x}")).collect()
@@ -38,6 +537,7 @@ mod hex {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::request::RequestInfo;
 
     #[test]
     fn hash_produces_stable_output() {
@@ -54,6 +554,303 @@ mod tests {
         assert_ne!(hash_consumer_id("a"), hash_consumer_id("b"));
     }
 
+    #[test]
+    fn keyed_hash_depends_on_secret() {
+        let a = hash_consumer_id_keyed("Bearer token123", b"secret-a", 32);
+        assert!(a.starts_with("hmac_"));
+        assert_eq!(a.len(), 5 + 32);
+        assert_eq!(
+            a,
+            hash_consumer_id_keyed("Bearer token123", b"secret-a", 32)
+        );
+        assert_ne!(
+            a,
+            hash_consumer_id_keyed("Bearer token123", b"secret-b", 32)
+        );
+    }
+
+    #[test]
+    fn keyed_hash_length_is_clamped() {
+        assert_eq!(hash_consumer_id_keyed("x", b"k", 4).len(), 5 + 16);
+        assert_eq!(hash_consumer_id_keyed("x", b"k", 40).len(), 5 + 40);
+        assert_eq!(hash_consumer_id_keyed("x", b"k", 500).len(), 5 + 64);
+    }
+
+    #[test]
+    fn default_identify_with_keyed_hashing() {
+        let hashing = ConsumerHashing::new("s3cret");
+        let policy = ConsumerIdPolicy {
+            hashing: Some(hashing.clone()),
+            ..Default::default()
+        };
+        let id = default_identify_consumer_with(
+            |name| (name == "authorization").then(|| "Bearer secret".to_string()),
+            &policy,
+        );
+        assert_eq!(id, Some(hashing.hash("Bearer secret")));
+    }
+
+    fn make_jwt(claims: &str, alg: &str, secret: &[u8]) -> String {
+        let header = URL_SAFE_NO_PAD.encode(format!(r#"{{"alg":"{alg}","typ":"JWT"}}"#));
+        let payload = URL_SAFE_NO_PAD.encode(claims);
+        let signing_input = format!("{header}.{payload}");
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(signing_input.as_bytes());
+        let sig = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signing_input}.{sig}")
+    }
+
+    #[test]
+    fn jwt_claim_extraction() {
+        let token = make_jwt(r#"{"sub":"user-1","client_id":42}"#, "HS256", b"k");
+        assert_eq!(
+            JwtIdentifier::new("sub")
+                .claim_from_token(&token)
+                .as_deref(),
+            Some("user-1")
+        );
+        assert_eq!(
+            JwtIdentifier::new("client_id")
+                .claim_from_token(&token)
+                .as_deref(),
+            Some("42")
+        );
+        assert!(JwtIdentifier::new("azp").claim_from_token(&token).is_none());
+        assert!(JwtIdentifier::new("sub")
+            .claim_from_token("not.a-jwt")
+            .is_none());
+    }
+
+    #[test]
+    fn jwt_signature_verification() {
+        let token = make_jwt(r#"{"sub":"user-1"}"#, "HS256", b"right");
+        let verified = JwtIdentifier::new("sub").verify_hmac("right");
+        assert_eq!(verified.claim_from_token(&token).as_deref(), Some("user-1"));
+
+        let wrong_key = JwtIdentifier::new("sub").verify_hmac("wrong");
+        assert!(wrong_key.claim_from_token(&token).is_none());
+
+        // Tampered payload
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(r#"{"sub":"admin"}"#);
+        parts[1] = &forged;
+        assert!(verified.claim_from_token(&parts.join(".")).is_none());
+
+        // alg=none is never accepted when verifying
+        let unsigned = make_jwt(r#"{"sub":"user-1"}"#, "none", b"right");
+        assert!(verified.claim_from_token(&unsigned).is_none());
+    }
+
+    fn sign_jwt(claims: &str, alg: &str) -> String {
+        use ring::rand::SystemRandom;
+        use ring::signature::{self, EcdsaKeyPair, RsaKeyPair};
+        use rustls::pki_types::{pem::PemObject, PrivateKeyDer};
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(format!(r#"{{"alg":"{alg}","typ":"JWT"}}"#)),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let rng = SystemRandom::new();
+        let sig = if alg.starts_with("ES") {
+            let key =
+                PrivateKeyDer::from_pem_slice(include_bytes!("../tests/fixtures/jwt/ec-key.pem"))
+                    .unwrap();
+            let key = EcdsaKeyPair::from_pkcs8(
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                key.secret_der(),
+                &rng,
+            )
+            .unwrap();
+            key.sign(&rng, signing_input.as_bytes())
+                .unwrap()
+                .as_ref()
+                .to_vec()
+        } else {
+            let key =
+                PrivateKeyDer::from_pem_slice(include_bytes!("../tests/fixtures/jwt/rsa-key.pem"))
+                    .unwrap();
+            let key = RsaKeyPair::from_pkcs8(key.secret_der()).unwrap();
+            let padding: &dyn signature::RsaEncoding = match alg {
+                "PS256" => &signature::RSA_PSS_SHA256,
+                _ => &signature::RSA_PKCS1_SHA256,
+            };
+            let mut sig = vec![0; key.public().modulus_len()];
+            key.sign(padding, &rng, signing_input.as_bytes(), &mut sig)
+                .unwrap();
+            sig
+        };
+        format!("{signing_input}.{}", URL_SAFE_NO_PAD.encode(sig))
+    }
+
+    #[test]
+    fn jwt_public_key_verification() {
+        let rsa = JwtIdentifier::new("sub").verify_public_key(
+            JwtPublicKey::from_pem(include_str!("../tests/fixtures/jwt/rsa-public.pem")).unwrap(),
+        );
+        let ec = JwtIdentifier::new("sub").verify_public_key(
+            JwtPublicKey::from_pem(include_str!("../tests/fixtures/jwt/ec-public.pem")).unwrap(),
+        );
+        for alg in ["RS256", "PS256"] {
+            let token = sign_jwt(r#"{"sub":"user-1"}"#, alg);
+            assert_eq!(rsa.verify_token(&token).as_deref(), Ok("user-1"), "{alg}");
+            assert!(ec.verify_token(&token).unwrap_err().contains("P-256"));
+        }
+        let token = sign_jwt(r#"{"sub":"user-1"}"#, "ES256");
+        assert_eq!(ec.verify_token(&token).as_deref(), Ok("user-1"));
+        assert!(rsa.verify_token(&token).unwrap_err().contains("RSA"));
+
+        // Tampered payload
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(r#"{"sub":"admin"}"#);
+        parts[1] = &forged;
+        assert_eq!(
+            ec.verify_token(&parts.join(".")).unwrap_err(),
+            "[peekapi] JWT signature does not verify"
+        );
+
+        // HMAC tokens need an HMAC secret, even when a public key is set
+        let hs256 = make_jwt(r#"{"sub":"user-1"}"#, "HS256", b"k");
+        assert!(rsa
+            .verify_token(&hs256)
+            .unwrap_err()
+            .contains("HMAC secret"));
+    }
+
+    #[test]
+    fn unsupported_jwt_algorithms_are_reported() {
+        let verified = JwtIdentifier::new("sub").verify_hmac("k");
+        for alg in ["ES512", "EdDSA", "none"] {
+            let token = make_jwt(r#"{"sub":"user-1"}"#, alg, b"k");
+            assert_eq!(
+                verified.verify_token(&token).unwrap_err(),
+                format!("[peekapi] Unsupported JWT algorithm: {alg}")
+            );
+        }
+        let token = make_jwt(r#"{"sub":"user-1"}"#, "RS256", b"k");
+        assert!(verified
+            .verify_token(&token)
+            .unwrap_err()
+            .contains("needs a public key"));
+    }
+
+    #[test]
+    fn default_identify_uses_jwt_claim_with_hash_fallback() {
+        let policy = ConsumerIdPolicy {
+            jwt: Some(JwtIdentifier::new("sub")),
+            ..Default::default()
+        };
+        let token = make_jwt(r#"{"sub":"user-1"}"#, "HS256", b"k");
+        let id = default_identify_consumer_with(
+            |name| (name == "authorization").then(|| format!("Bearer {token}")),
+            &policy,
+        );
+        assert_eq!(id.as_deref(), Some("user-1"));
+
+        let opaque = default_identify_consumer_with(
+            |name| (name == "authorization").then(|| "Bearer opaque-token".to_string()),
+            &policy,
+        );
+        assert_eq!(opaque, Some(hash_consumer_id("Bearer opaque-token")));
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestInfo {
+        headers
+            .iter()
+            .fold(RequestInfo::new("GET", "/"), |req, (k, v)| {
+                req.with_header(*k, *v)
+            })
+    }
+
+    #[test]
+    fn chain_uses_first_matching_step() {
+        let chain = ConsumerChain::new()
+            .header("X-Client-Id", IdEncoding::Raw)
+            .cookie("tenant", IdEncoding::Raw)
+            .query_param("client", IdEncoding::Hash)
+            .authorization();
+        let policy = ConsumerIdPolicy::default();
+
+        let mut all = request(&[
+            ("x-client-id", "svc-a"),
+            ("cookie", "a=1; tenant=acme"),
+            ("authorization", "Bearer tok"),
+        ]);
+        all.query = Some("client=c1".to_string());
+        assert_eq!(chain.identify(&all, &policy).as_deref(), Some("svc-a"));
+
+        let cookie = request(&[("cookie", "a=1; tenant=acme"), ("x-client-id", "")]);
+        assert_eq!(chain.identify(&cookie, &policy).as_deref(), Some("acme"));
+
+        let mut query = request(&[]);
+        query.query = Some("x=1&client=c1".to_string());
+        assert_eq!(
+            chain.identify(&query, &policy),
+            Some(hash_consumer_id("c1"))
+        );
+        assert_eq!(chain.identify(&request(&[]), &policy), None);
+
+        let auth = request(&[("authorization", "Bearer tok")]);
+        assert_eq!(
+            chain.identify(&auth, &policy),
+            Some(hash_consumer_id("Bearer tok"))
+        );
+    }
+
+    #[test]
+    fn chain_basic_auth_and_client_certificates() {
+        let policy = ConsumerIdPolicy::default();
+        let chain = ConsumerChain::new()
+            .client_certificate("x-forwarded-client-cert", IdEncoding::Raw)
+            .client_certificate("x-ssl-client-s-dn", IdEncoding::Raw)
+            .basic_auth_username(IdEncoding::Raw);
+
+        // "alice:secret"
+        let basic = request(&[("authorization", "Basic YWxpY2U6c2VjcmV0")]);
+        assert_eq!(chain.identify(&basic, &policy).as_deref(), Some("alice"));
+
+        let envoy = request(&[(
+            "x-forwarded-client-cert",
+            r#"By=spiffe://mesh/a;Hash=abc;Subject="CN=svc-b,O=Acme";URI=spiffe://mesh/b"#,
+        )]);
+        assert_eq!(
+            chain.identify(&envoy, &policy).as_deref(),
+            Some("CN=svc-b,O=Acme")
+        );
+
+        let nginx = request(&[("x-ssl-client-s-dn", "CN=svc-c,O=Acme")]);
+        assert_eq!(
+            chain.identify(&nginx, &policy).as_deref(),
+            Some("CN=svc-c,O=Acme")
+        );
+    }
+
+    #[test]
+    fn chain_reads_path_and_request_identities() {
+        let policy = ConsumerIdPolicy::default();
+        let chain = ConsumerChain::new()
+            .identity()
+            .peer_identity(IdEncoding::Raw)
+            .path_segment(1, IdEncoding::Raw);
+
+        let tenant = RequestInfo::new("GET", "/tenants/acme/orders");
+        assert_eq!(chain.identify(&tenant, &policy).as_deref(), Some("acme"));
+
+        let mut peer = tenant.clone();
+        peer.peer_identity = Some("spiffe://mesh/billing".to_string());
+        assert_eq!(
+            chain.identify(&peer, &policy).as_deref(),
+            Some("spiffe://mesh/billing")
+        );
+
+        let mut authed = peer.clone();
+        authed.consumer_identity = Some("customer-7".to_string());
+        assert_eq!(
+            chain.identify(&authed, &policy).as_deref(),
+            Some("customer-7")
+        );
+    }
+
     #[test]
     fn default_identify_prefers_api_key() {
         let id = default_identify_consumer(|name| match name {
@@ -61,7 +858,58 @@ mod tests {
             "authorization" => Some("Bearer secret".to_string()),
             _ => None,
         });
-        assert_eq!(id, Some("ak_test_123".to_string()));
+        assert_eq!(id, Some(hash_consumer_id("ak_test_123")));
+    }
+
+    fn api_key_header(name: &str) -> Option<String> {
+        (name == "x-api-key").then(|| "ak_live_1234567890abcdef".to_string())
+    }
+
+    #[test]
+    fn api_key_policy_raw_and_prefix() {
+        let raw = ConsumerIdPolicy {
+            api_key: ApiKeyPolicy::Raw,
+            ..Default::default()
+        };
+        assert_eq!(
+            default_identify_consumer_with(api_key_header, &raw).as_deref(),
+            Some("ak_live_1234567890abcdef")
+        );
+
+        let prefix = ConsumerIdPolicy {
+            api_key: ApiKeyPolicy::Prefix(12),
+            ..Default::default()
+        };
+        assert_eq!(
+            default_identify_consumer_with(api_key_header, &prefix).as_deref(),
+            Some("ak_live_1234…")
+        );
+
+        // Never reveals more than half of a short key
+        let short = default_identify_consumer_with(
+            |name| (name == "x-api-key").then(|| "abcd".to_string()),
+            &prefix,
+        );
+        assert_eq!(short.as_deref(), Some("ab…"));
+    }
+
+    #[test]
+    fn api_key_policy_lookup_falls_back_to_hash() {
+        let policy = ConsumerIdPolicy {
+            api_key: ApiKeyPolicy::Lookup(Arc::new(|key| {
+                (key == "ak_live_1234567890abcdef").then(|| "customer-42".to_string())
+            })),
+            ..Default::default()
+        };
+        assert_eq!(
+            default_identify_consumer_with(api_key_header, &policy).as_deref(),
+            Some("customer-42")
+        );
+        let unknown = default_identify_consumer_with(
+            |name| (name == "x-api-key").then(|| "ak_other".to_string()),
+            &policy,
+        );
+        assert_eq!(unknown, Some(hash_consumer_id("ak_other")));
     }
 
     #[test]
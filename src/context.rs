@@ -0,0 +1,122 @@
+use crate::types::RequestEvent;
+
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex};
+
+/// Per-request handle that lets handlers contribute to the tracked event.
+///
+/// The middleware adapters insert a `RequestContext` into each request
+/// before calling the handler and merge it into the `RequestEvent` once the
+/// response is ready:
+///
+///   - Actix Web: extract `RequestContext` as a handler argument, or read it
+///     from `HttpRequest::extensions()`
+///   - Axum: extract `Extension<RequestContext>`
+///   - Rocket: use `RequestContext` as a request guard
+///
+/// Cloning is cheap; all clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    inner: Arc<Mutex<ContextState>>,
+}
+
+#[derive(Debug, Default)]
+struct ContextState {
+    metadata: Map<String, Value>,
+    consumer_id: Option<String>,
+    skip: bool,
+}
+
+impl RequestContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach a metadata value to the event (e.g. tenant ID, plan tier).
+    pub fn set_metadata(&self, key: impl Into<String>, value: impl Into<Value>) {
+        self.inner
+            .lock()
+            .unwrap()
+            .metadata
+            .insert(key.into(), value.into());
+    }
+
+    /// Override the consumer ID identified from request headers.
+    pub fn set_consumer_id(&self, consumer_id: impl Into<String>) {
+        self.inner.lock().unwrap().consumer_id = Some(consumer_id.into());
+    }
+
+    /// Suppress tracking for this request.
+    pub fn skip(&self) {
+        self.inner.lock().unwrap().skip = true;
+    }
+
+    /// Whether tracking has been suppressed for this request.
+    pub fn is_skipped(&self) -> bool {
+        self.inner.lock().unwrap().skip
+    }
+
+    /// Merge handler contributions into `event`. Called by the middleware
+    /// adapters; useful when building events for `PeekApiClient::track` by hand.
+    ///
+    /// Returns `false` if the request should not be tracked.
+    pub fn apply(&self, event: &mut RequestEvent) -> bool {
+        let mut state = self.inner.lock().unwrap();
+        if state.skip {
+            return false;
+        }
+        if let Some(consumer_id) = state.consumer_id.take() {
+            event.consumer_id = Some(consumer_id);
+        }
+        for (key, value) in std::mem::take(&mut state.metadata) {
+            event.merge_metadata(&key, value);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_merges_metadata_and_consumer() {
+        let ctx = RequestContext::new();
+        let handle = ctx.clone();
+        handle.set_metadata("tenant_id", "t-42");
+        handle.set_metadata("plan", "pro");
+        handle.set_consumer_id("customer-7");
+
+        let mut event = RequestEvent {
+            consumer_id: Some("hash_abc".to_string()),
+            ..Default::default()
+        };
+        event.merge_metadata("headers", Value::from(1));
+
+        assert!(ctx.apply(&mut event));
+        assert_eq!(event.consumer_id.as_deref(), Some("customer-7"));
+        let meta = event.metadata.unwrap();
+        assert_eq!(meta["tenant_id"], "t-42");
+        assert_eq!(meta["plan"], "pro");
+        assert_eq!(meta["headers"], 1);
+    }
+
+    #[test]
+    fn apply_without_contributions_leaves_event_unchanged() {
+        let mut event = RequestEvent {
+            consumer_id: Some("hash_abc".to_string()),
+            ..Default::default()
+        };
+        assert!(RequestContext::new().apply(&mut event));
+        assert_eq!(event.consumer_id.as_deref(), Some("hash_abc"));
+        assert!(event.metadata.is_none());
+    }
+
+    #[test]
+    fn skip_suppresses_tracking() {
+        let ctx = RequestContext::new();
+        ctx.clone().skip();
+        assert!(ctx.is_skipped());
+        assert!(!ctx.apply(&mut RequestEvent::default()));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::types::RequestEvent;
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::sync::Mutex;
+
+/// Why an event was moved to the dead-letter store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterReason {
+    /// The ingestion API rejected this event in its per-event results.
+    Rejected,
+    /// The ingestion API refused the whole batch as malformed (400 or
+    /// 422), or a single event was too large (413).
+    ClientError,
+}
+
+/// An event set aside because the ingestion API will not accept it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub reason: DeadLetterReason,
+    /// Rejection reason from the ingestion API, or the delivery error.
+    pub detail: String,
+    /// When the event was dead-lettered (ISO 8601, UTC).
+    pub failed_at: String,
+    pub event: RequestEvent,
+}
+
+/// JSONL file of [`DeadLetter`] records, one per line, capped in size.
+pub(crate) struct DeadLetterStore {
+    path: String,
+    max_bytes: u64,
+    // Serializes appends against export and purge
+    lock: Mutex<()>,
+}
+
+impl DeadLetterStore {
+    pub fn new(path: String, max_bytes: u64) -> Self {
+        Self {
+            path,
+            max_bytes,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Append `letters` until the file would exceed the cap. Returns how
+    /// many were written.
+    pub fn append(&self, letters: &[DeadLetter]) -> Result<usize, String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        let mut data = String::new();
+        let mut written = 0;
+        for letter in letters {
+            let Ok(line) = serde_json::to_string(letter) else {
+                continue;
+            };
+            if size + line.len() as u64 + 1 > self.max_bytes {
+                break;
+            }
+            size += line.len() as u64 + 1;
+            data.push_str(&line);
+            data.push('\n');
+            written += 1;
+        }
+        if written == 0 {
+            return Ok(0);
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(data.as_bytes()))
+            .map_err(|e| format!("[peekapi] Cannot write dead-letter file {}: {e}", self.path))?;
+        Ok(written)
+    }
+
+    /// All readable records, oldest first. Corrupt lines are skipped.
+    pub fn read(&self) -> Result<Vec<DeadLetter>, String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut letters = Vec::new();
+        self.for_each_line(|line| {
+            if let Ok(letter) = serde_json::from_str(line) {
+                letters.push(letter);
+            }
+            Ok(())
+        })?;
+        Ok(letters)
+    }
+
+    /// Copy all readable records to `out` as JSONL. Returns the count.
+    pub fn export(&self, out: &mut dyn Write) -> Result<usize, String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut exported = 0;
+        self.for_each_line(|line| {
+            if serde_json::from_str::<DeadLetter>(line).is_err() {
+                return Ok(());
+            }
+            writeln!(out, "{line}")
+                .map_err(|e| format!("[peekapi] Cannot export dead letters: {e}"))?;
+            exported += 1;
+            Ok(())
+        })?;
+        out.flush()
+            .map_err(|e| format!("[peekapi] Cannot export dead letters: {e}"))?;
+        Ok(exported)
+    }
+
+    /// Delete the store. Returns how many records it held.
+    pub fn purge(&self) -> Result<usize, String> {
+        let _guard = self.lock.lock().unwrap();
+        let mut count = 0;
+        self.for_each_line(|_| {
+            count += 1;
+            Ok(())
+        })?;
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(count),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(format!(
+                "[peekapi] Cannot remove dead-letter file {}: {e}",
+                self.path
+            )),
+        }
+    }
+
+    fn for_each_line(&self, mut f: impl FnMut(&str) -> Result<(), String>) -> Result<(), String> {
+        let read_error = |e: std::io::Error| {
+            format!("[peekapi] Cannot read dead-letter file {}: {e}", self.path)
+        };
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(read_error(e)),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(read_error)?;
+            let line = line.trim();
+            if !line.is_empty() {
+                f(line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn letter(path: &str) -> DeadLetter {
+        DeadLetter {
+            reason: DeadLetterReason::Rejected,
+            detail: "invalid path".to_string(),
+            failed_at: "2026-01-01T00:00:00.000Z".to_string(),
+            event: RequestEvent {
+                path: path.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn store(dir: &tempfile::TempDir, max_bytes: u64) -> DeadLetterStore {
+        let path = dir.path().join("dead.jsonl");
+        DeadLetterStore::new(path.to_str().unwrap().to_string(), max_bytes)
+    }
+
+    #[test]
+    fn append_read_and_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir, 1 << 20);
+        assert!(store.read().unwrap().is_empty());
+        assert_eq!(store.purge().unwrap(), 0);
+
+        assert_eq!(store.append(&[letter("/a"), letter("/b")]).unwrap(), 2);
+        assert_eq!(store.append(&[letter("/c")]).unwrap(), 1);
+        let letters = store.read().unwrap();
+        let paths: Vec<&str> = letters.iter().map(|l| l.event.path.as_str()).collect();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+        assert_eq!(letters[0].reason, DeadLetterReason::Rejected);
+
+        assert_eq!(store.purge().unwrap(), 3);
+        assert!(store.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn append_stops_at_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let line = serde_json::to_string(&letter("/a")).unwrap().len() as u64 + 1;
+        let store = store(&dir, line * 2);
+        assert_eq!(
+            store
+                .append(&[letter("/a"), letter("/b"), letter("/c")])
+                .unwrap(),
+            2
+        );
+        assert_eq!(store.append(&[letter("/d")]).unwrap(), 0);
+        assert_eq!(store.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn export_skips_corrupt_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir, 1 << 20);
+        store.append(&[letter("/a")]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(store.path()).unwrap();
+        writeln!(file, "{{not json").unwrap();
+        store.append(&[letter("/b")]).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(store.export(&mut out).unwrap(), 2);
+        let exported = String::from_utf8(out).unwrap();
+        assert_eq!(exported.lines().count(), 2);
+        assert!(exported.contains("\"reason\":\"rejected\""));
+        assert_eq!(store.read().unwrap().len(), 2);
+    }
+}
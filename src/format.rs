@@ -0,0 +1,367 @@
+use crate::idempotency::{new_id, Batch};
+use crate::types::RequestEvent;
+
+use serde::{Deserialize, Serialize};
+
+/// Serialization of event batches, for ingestion requests and the storage
+/// file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadFormat {
+    /// One JSON array per batch (`application/json`). On disk, one
+    /// `{"batch_id", "events"}` object per line.
+    #[default]
+    Json,
+    /// One JSON object per event and line (`application/x-ndjson`).
+    /// Streamable; on disk, each event also carries its `batch_id`.
+    Ndjson,
+    /// A MessagePack array of event maps (`application/msgpack`). Compact
+    /// binary; on disk, each `{batch_id, events}` map is framed with a
+    /// marker byte and its length.
+    MessagePack,
+}
+
+impl PayloadFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+            Self::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// Encode a batch as a request body.
+    pub(crate) fn encode(self, events: &[RequestEvent]) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => {
+                serde_json::to_vec(events).map_err(|e| format!("JSON marshal failed: {e}"))
+            }
+            Self::Ndjson => {
+                let mut out = Vec::new();
+                for event in events {
+                    serde_json::to_writer(&mut out, event)
+                        .map_err(|e| format!("JSON marshal failed: {e}"))?;
+                    out.push(b'\n');
+                }
+                Ok(out)
+            }
+            // Field names are kept: positional encoding can't represent the
+            // optional fields that are skipped when unset
+            Self::MessagePack => rmp_serde::to_vec_named(events)
+                .map_err(|e| format!("MessagePack encode failed: {e}")),
+        }
+    }
+
+    /// Encode a batch, with its ID, as appended to the storage file.
+    pub(crate) fn encode_record(self, batch: &Batch) -> Result<Vec<u8>, String> {
+        let record = BatchRecord {
+            batch_id: &batch.id,
+            events: &batch.events,
+        };
+        match self {
+            Self::Json => {
+                let mut out =
+                    serde_json::to_vec(&record).map_err(|e| format!("JSON marshal failed: {e}"))?;
+                out.push(b'\n');
+                Ok(out)
+            }
+            Self::Ndjson => {
+                let mut out = Vec::new();
+                for event in &batch.events {
+                    let line = EventRecord {
+                        batch_id: batch.id.clone(),
+                        event: event.clone(),
+                    };
+                    serde_json::to_writer(&mut out, &line)
+                        .map_err(|e| format!("JSON marshal failed: {e}"))?;
+                    out.push(b'\n');
+                }
+                Ok(out)
+            }
+            Self::MessagePack => {
+                let payload = rmp_serde::to_vec_named(&record)
+                    .map_err(|e| format!("MessagePack encode failed: {e}"))?;
+                let len = u32::try_from(payload.len())
+                    .map_err(|_| "MessagePack record too large".to_string())?;
+                let mut out = Vec::with_capacity(5 + payload.len());
+                out.push(FRAME_MARKER);
+                out.extend(len.to_be_bytes());
+                out.extend(payload);
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Starts a MessagePack storage record, followed by the record's length (u32,
+/// big-endian). 0xc1 is never used in MessagePack and never starts a JSON
+/// line, so records of all formats can share a file.
+const FRAME_MARKER: u8 = 0xc1;
+
+#[derive(Serialize)]
+struct BatchRecord<'a> {
+    batch_id: &'a str,
+    events: &'a [RequestEvent],
+}
+
+#[derive(Deserialize)]
+struct OwnedBatchRecord {
+    batch_id: String,
+    events: Vec<RequestEvent>,
+}
+
+/// An NDJSON storage line: the event with its batch ID alongside. Lines
+/// without one (written before batch IDs) are read as plain events.
+#[derive(Serialize, Deserialize)]
+struct EventRecord {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    batch_id: String,
+    #[serde(flatten)]
+    event: RequestEvent,
+}
+
+/// Decode a storage file written in any format, so events survive a change
+/// of `storage_format`. The format is detected per record, so a file holding
+/// records of several formats is read whole. Unreadable records are skipped.
+/// Events stored without a batch ID (older files) are grouped into new
+/// batches.
+pub(crate) fn decode_records(data: &[u8]) -> Vec<Batch> {
+    let mut batches: Vec<Batch> = Vec::new();
+    // NDJSON lines of the batch being assembled (empty ID: legacy events)
+    let mut open: Option<Batch> = None;
+    let mut rest = data;
+    while let Some(&first) = rest.first() {
+        if first == FRAME_MARKER {
+            match read_frame(rest) {
+                Some((record, tail)) => {
+                    batches.extend(open.take());
+                    batches.push(record);
+                    rest = tail;
+                }
+                None => rest = resync(&rest[1..]),
+            }
+        } else if first.is_ascii_whitespace() || first == b'{' || first == b'[' {
+            let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            decode_line(&rest[..end], &mut batches, &mut open);
+            rest = rest.get(end + 1..).unwrap_or_default();
+        } else {
+            // Unframed MessagePack, as written by older versions
+            let mut reader = rest;
+            match rmp_serde::from_read::<_, Vec<RequestEvent>>(&mut reader) {
+                Ok(events) => {
+                    batches.extend(open.take());
+                    batches.push(Batch::new(events));
+                    rest = reader;
+                }
+                Err(_) => rest = resync(&rest[1..]),
+            }
+        }
+    }
+    batches.extend(open);
+    for batch in &mut batches {
+        if batch.id.is_empty() {
+            batch.id = new_id();
+        }
+    }
+    batches
+}
+
+/// Read one framed MessagePack record: the batch and the data after it.
+fn read_frame(data: &[u8]) -> Option<(Batch, &[u8])> {
+    let len = u32::from_be_bytes(data.get(1..5)?.try_into().ok()?) as usize;
+    let payload = data.get(5..5 + len)?;
+    let record: OwnedBatchRecord = rmp_serde::from_slice(payload).ok()?;
+    let batch = Batch {
+        id: record.batch_id,
+        events: record.events,
+    };
+    Some((batch, &data[5 + len..]))
+}
+
+/// Skip to the next record after unreadable data: the next frame marker or
+/// the start of the next line.
+fn resync(data: &[u8]) -> &[u8] {
+    match data.iter().position(|&b| b == FRAME_MARKER || b == b'\n') {
+        Some(i) if data[i] == b'\n' => &data[i + 1..],
+        Some(i) => &data[i..],
+        None => &[],
+    }
+}
+
+/// Decode one JSON storage line: a batch record, a legacy array, or an
+/// NDJSON event added to the `open` batch. Unreadable lines are skipped.
+fn decode_line(line: &[u8], batches: &mut Vec<Batch>, open: &mut Option<Batch>) {
+    if let Ok(record) = serde_json::from_slice::<OwnedBatchRecord>(line) {
+        batches.extend(open.take());
+        batches.push(Batch {
+            id: record.batch_id,
+            events: record.events,
+        });
+    } else if let Ok(events) = serde_json::from_slice::<Vec<RequestEvent>>(line) {
+        batches.extend(open.take());
+        batches.push(Batch::new(events));
+    } else if let Ok(record) = serde_json::from_slice::<EventRecord>(line) {
+        match open {
+            Some(ref mut batch) if batch.id == record.batch_id => batch.events.push(record.event),
+            _ => {
+                batches.extend(open.take());
+                *open = Some(Batch {
+                    id: record.batch_id,
+                    events: vec![record.event],
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> Vec<RequestEvent> {
+        vec![
+            RequestEvent {
+                method: "GET".to_string(),
+                path: "/a".to_string(),
+                status_code: 200,
+                response_time_ms: 1.5,
+                consumer_id: Some("alice".to_string()),
+                metadata: Some(serde_json::json!({"tier": "pro", "n": [1, 2]})),
+                event_id: "evt_1".to_string(),
+                ..Default::default()
+            },
+            RequestEvent {
+                method: "POST".to_string(),
+                path: "/b".to_string(),
+                status_code: 500,
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn paths(events: &[RequestEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    #[test]
+    fn ndjson_has_one_event_per_line() {
+        let body = PayloadFormat::Ndjson.encode(&batch()).unwrap();
+        let body = String::from_utf8(body).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(body.ends_with('\n'));
+        let first: RequestEvent = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first.path, "/a");
+    }
+
+    #[test]
+    fn message_pack_round_trips() {
+        let body = PayloadFormat::MessagePack.encode(&batch()).unwrap();
+        let decoded: Vec<RequestEvent> = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(paths(&decoded), ["/a", "/b"]);
+        assert_eq!(decoded[0].consumer_id.as_deref(), Some("alice"));
+        assert_eq!(decoded[0].metadata, batch()[0].metadata);
+        assert_eq!(decoded[0].event_id, "evt_1");
+        assert!(decoded[1].consumer_id.is_none());
+        assert!(body.len() < PayloadFormat::Json.encode(&batch()).unwrap().len());
+    }
+
+    fn record(format: PayloadFormat, id: &str, events: &[RequestEvent]) -> Vec<u8> {
+        let batch = Batch {
+            id: id.to_string(),
+            events: events.to_vec(),
+        };
+        format.encode_record(&batch).unwrap()
+    }
+
+    fn ids_and_paths(batches: &[Batch]) -> Vec<(&str, Vec<&str>)> {
+        batches
+            .iter()
+            .map(|b| (b.id.as_str(), paths(&b.events)))
+            .collect()
+    }
+
+    #[test]
+    fn storage_records_keep_batches_and_ids() {
+        for format in [
+            PayloadFormat::Json,
+            PayloadFormat::Ndjson,
+            PayloadFormat::MessagePack,
+        ] {
+            let mut file = record(format, "b1", &batch());
+            file.extend(record(format, "b2", &batch()[..1]));
+            assert_eq!(
+                ids_and_paths(&decode_records(&file)),
+                [("b1", vec!["/a", "/b"]), ("b2", vec!["/a"])],
+                "{format:?}"
+            );
+        }
+        assert!(decode_records(b"").is_empty());
+    }
+
+    #[test]
+    fn legacy_records_get_new_batch_ids() {
+        // One array per line, and one event per line, without batch IDs
+        let mut file = PayloadFormat::Json.encode(&batch()).unwrap();
+        file.push(b'\n');
+        file.extend(PayloadFormat::Ndjson.encode(&batch()).unwrap());
+        let batches = decode_records(&file);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(paths(&batches[0].events), ["/a", "/b"]);
+        assert_eq!(paths(&batches[1].events), ["/a", "/b"]);
+        assert_eq!(batches[0].id.len(), 36);
+        assert_ne!(batches[0].id, batches[1].id);
+
+        // MessagePack arrays back to back
+        let mut file = PayloadFormat::MessagePack.encode(&batch()).unwrap();
+        file.extend(record(PayloadFormat::MessagePack, "b1", &batch()[..1]));
+        let batches = decode_records(&file);
+        assert_eq!(paths(&batches[0].events), ["/a", "/b"]);
+        assert_eq!(batches[0].id.len(), 36);
+        assert_eq!(ids_and_paths(&batches[1..]), [("b1", vec!["/a"])]);
+    }
+
+    #[test]
+    fn unreadable_records_are_skipped() {
+        let mut file = record(PayloadFormat::Json, "b1", &batch());
+        file.extend(b"{corrupt\n");
+        file.extend(record(PayloadFormat::Ndjson, "b2", &batch()));
+        assert_eq!(
+            ids_and_paths(&decode_records(&file)),
+            [("b1", vec!["/a", "/b"]), ("b2", vec!["/a", "/b"])]
+        );
+
+        // A corrupt MessagePack record, and one cut short, don't hide the
+        // records after them
+        let mut corrupt = record(PayloadFormat::MessagePack, "b2", &batch());
+        corrupt[8] ^= 0xff;
+        let truncated = record(PayloadFormat::MessagePack, "b3", &batch());
+        let mut file = record(PayloadFormat::MessagePack, "b1", &batch());
+        file.extend(corrupt);
+        file.extend(&truncated[..truncated.len() / 2]);
+        file.extend(b"garbage");
+        file.extend(record(PayloadFormat::MessagePack, "b4", &batch()));
+        assert_eq!(
+            ids_and_paths(&decode_records(&file)),
+            [("b1", vec!["/a", "/b"]), ("b4", vec!["/a", "/b"])]
+        );
+    }
+
+    #[test]
+    fn mixed_format_records_are_all_read() {
+        let mut file = record(PayloadFormat::MessagePack, "b1", &batch());
+        file.extend(record(PayloadFormat::Json, "b2", &batch()));
+        file.extend(record(PayloadFormat::MessagePack, "b3", &batch()[..1]));
+        file.extend(record(PayloadFormat::Ndjson, "b4", &batch()));
+        file.extend(record(PayloadFormat::MessagePack, "b5", &batch()[1..]));
+        assert_eq!(
+            ids_and_paths(&decode_records(&file)),
+            [
+                ("b1", vec!["/a", "/b"]),
+                ("b2", vec!["/a", "/b"]),
+                ("b3", vec!["/a"]),
+                ("b4", vec!["/a", "/b"]),
+                ("b5", vec!["/b"]),
+            ]
+        );
+    }
+}
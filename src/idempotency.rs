@@ -0,0 +1,93 @@
+use crate::consumer::hex;
+use crate::types::RequestEvent;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header carrying the batch ID.
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Events sent in one request. The ID is the request's idempotency key and
+/// stays with the batch through retries and the storage file, so a resend
+/// of a batch the ingestion API already stored can be recognized.
+#[derive(Debug)]
+pub(crate) struct Batch {
+    pub id: String,
+    pub events: Vec<RequestEvent>,
+}
+
+impl Batch {
+    /// A batch with a fresh ID.
+    pub fn new(events: Vec<RequestEvent>) -> Self {
+        Self {
+            id: new_id(),
+            events,
+        }
+    }
+}
+
+/// New event or batch ID: a UUIDv7 (millisecond timestamp + 74 random
+/// bits), so IDs sort roughly by creation time.
+pub(crate) fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        fallback_random(&mut bytes);
+    }
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x70; // version 7
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+
+    let hex = hex::encode(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Randomly keyed SipHash over a process-wide counter, for platforms where
+/// the OS random source is unavailable.
+fn fallback_random(bytes: &mut [u8; 16]) {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    for chunk in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_uuid_v7() {
+        let id = new_id();
+        assert_eq!(id.len(), 36);
+        let parts: Vec<&str> = id.split('-').collect();
+        assert_eq!(
+            parts.iter().map(|p| p.len()).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12]
+        );
+        assert!(parts[2].starts_with('7'), "{id}");
+        assert!(matches!(&parts[3][..1], "8" | "9" | "a" | "b"), "{id}");
+        assert_ne!(id, new_id());
+    }
+
+    #[test]
+    fn fallback_random_differs_per_call() {
+        let (mut a, mut b) = ([0u8; 16], [0u8; 16]);
+        fallback_random(&mut a);
+        fallback_random(&mut b);
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,257 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::signature::{
+    self, RsaParameters, RsaPublicKeyComponents, UnparsedPublicKey, VerificationAlgorithm,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::SubjectPublicKeyInfoDer;
+
+// DER-encoded object identifiers (without tag and length)
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Public key for verifying RS256/384/512, PS256/384/512, ES256 and ES384
+/// JWT signatures.
+#[derive(Clone, PartialEq, Eq)]
+pub struct JwtPublicKey(Key);
+
+#[derive(Clone, PartialEq, Eq)]
+enum Key {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    // Uncompressed curve points (0x04 || x || y)
+    P256(Vec<u8>),
+    P384(Vec<u8>),
+}
+
+impl JwtPublicKey {
+    /// Load a PEM `PUBLIC KEY` (SubjectPublicKeyInfo) holding an RSA, P-256
+    /// or P-384 key, as printed by `openssl pkey -pubout`.
+    pub fn from_pem(pem: &str) -> Result<Self, String> {
+        let spki = SubjectPublicKeyInfoDer::from_pem_slice(pem.as_bytes())
+            .map_err(|e| format!("[peekapi] Invalid JWT public key PEM: {e}"))?;
+        parse_spki(&spki).ok_or_else(|| {
+            "[peekapi] Invalid JWT public key: expected an RSA, P-256 or P-384 key".to_string()
+        })
+    }
+
+    /// Load a JSON Web Key: `{"kty":"RSA","n":..,"e":..}` or
+    /// `{"kty":"EC","crv":"P-256"|"P-384","x":..,"y":..}`.
+    pub fn from_jwk(jwk: &str) -> Result<Self, String> {
+        let jwk: serde_json::Value =
+            serde_json::from_str(jwk).map_err(|e| format!("[peekapi] Invalid JWK: {e}"))?;
+        let field = |name: &str| -> Result<Vec<u8>, String> {
+            let value = jwk
+                .get(name)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| format!("[peekapi] Invalid JWK: missing \"{name}\""))?;
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map_err(|e| format!("[peekapi] Invalid JWK \"{name}\": {e}"))
+        };
+        match jwk.get("kty").and_then(|v| v.as_str()) {
+            Some("RSA") => Ok(Self(Key::Rsa {
+                n: strip_leading_zeros(&field("n")?),
+                e: strip_leading_zeros(&field("e")?),
+            })),
+            Some("EC") => {
+                let (x, y) = (field("x")?, field("y")?);
+                let (len, curve): (usize, fn(Vec<u8>) -> Key) =
+                    match jwk.get("crv").and_then(|v| v.as_str()) {
+                        Some("P-256") => (32, Key::P256),
+                        Some("P-384") => (48, Key::P384),
+                        crv => {
+                            return Err(format!("[peekapi] Unsupported JWK curve: {crv:?}"));
+                        }
+                    };
+                if x.len() != len || y.len() != len {
+                    return Err("[peekapi] Invalid JWK: wrong coordinate length".to_string());
+                }
+                let mut point = vec![0x04];
+                point.extend(x);
+                point.extend(y);
+                Ok(Self(curve(point)))
+            }
+            kty => Err(format!("[peekapi] Unsupported JWK key type: {kty:?}")),
+        }
+    }
+
+    /// Verify `signature` over `signed` for the JWT algorithm `alg`.
+    pub(crate) fn verify(&self, alg: &str, signed: &[u8], signature: &[u8]) -> Result<(), String> {
+        let valid = match (&self.0, alg) {
+            (Key::Rsa { n, e }, _) => {
+                let params: &RsaParameters = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                    "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                    "PS512" => &signature::RSA_PSS_2048_8192_SHA512,
+                    _ => return Err(mismatch(alg, "RSA")),
+                };
+                RsaPublicKeyComponents { n, e }
+                    .verify(params, signed, signature)
+                    .is_ok()
+            }
+            (Key::P256(point), "ES256") => verify_with(
+                &signature::ECDSA_P256_SHA256_FIXED,
+                point,
+                signed,
+                signature,
+            ),
+            (Key::P384(point), "ES384") => verify_with(
+                &signature::ECDSA_P384_SHA384_FIXED,
+                point,
+                signed,
+                signature,
+            ),
+            (Key::P256(_), _) => return Err(mismatch(alg, "P-256")),
+            (Key::P384(_), _) => return Err(mismatch(alg, "P-384")),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("[peekapi] JWT signature does not verify".to_string())
+        }
+    }
+}
+
+impl std::fmt::Debug for JwtPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.0 {
+            Key::Rsa { ref n, .. } => format!("RSA-{}", n.len() * 8),
+            Key::P256(_) => "P-256".to_string(),
+            Key::P384(_) => "P-384".to_string(),
+        };
+        f.debug_tuple("JwtPublicKey").field(&kind).finish()
+    }
+}
+
+fn mismatch(alg: &str, key: &str) -> String {
+    format!("[peekapi] JWT algorithm {alg} cannot be verified with the configured {key} key")
+}
+
+fn verify_with(
+    alg: &'static dyn VerificationAlgorithm,
+    point: &[u8],
+    signed: &[u8],
+    signature: &[u8],
+) -> bool {
+    UnparsedPublicKey::new(alg, point)
+        .verify(signed, signature)
+        .is_ok()
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> Vec<u8> {
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+/// SubjectPublicKeyInfo ::= SEQUENCE { algorithm AlgorithmIdentifier,
+/// subjectPublicKey BIT STRING }
+fn parse_spki(der: &[u8]) -> Option<JwtPublicKey> {
+    let (spki, _) = der_element(der, 0x30)?;
+    let (algorithm, rest) = der_element(spki, 0x30)?;
+    let (bits, _) = der_element(rest, 0x03)?;
+    let (&unused_bits, key) = bits.split_first()?;
+    if unused_bits != 0 {
+        return None;
+    }
+    let (oid, params) = der_element(algorithm, 0x06)?;
+    let key = match oid {
+        // RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+        OID_RSA_ENCRYPTION => {
+            let (rsa, _) = der_element(key, 0x30)?;
+            let (n, rest) = der_element(rsa, 0x02)?;
+            let (e, _) = der_element(rest, 0x02)?;
+            Key::Rsa {
+                n: strip_leading_zeros(n),
+                e: strip_leading_zeros(e),
+            }
+        }
+        OID_EC_PUBLIC_KEY => match der_element(params, 0x06)?.0 {
+            OID_P256 => Key::P256(key.to_vec()),
+            OID_P384 => Key::P384(key.to_vec()),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(JwtPublicKey(key))
+}
+
+/// Split one DER element with the given tag off `input`: (contents, rest).
+fn der_element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual, rest) = input.split_first()?;
+    if actual != tag {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[n..])
+    };
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_PUBLIC: &str = include_str!("../tests/fixtures/jwt/rsa-public.pem");
+    const EC_PUBLIC: &str = include_str!("../tests/fixtures/jwt/ec-public.pem");
+
+    #[test]
+    fn loads_pem_keys() {
+        let rsa = JwtPublicKey::from_pem(RSA_PUBLIC).unwrap();
+        assert_eq!(format!("{rsa:?}"), "JwtPublicKey(\"RSA-2048\")");
+        let ec = JwtPublicKey::from_pem(EC_PUBLIC).unwrap();
+        assert_eq!(format!("{ec:?}"), "JwtPublicKey(\"P-256\")");
+        assert!(JwtPublicKey::from_pem("not a key").is_err());
+    }
+
+    #[test]
+    fn jwk_matches_pem() {
+        let Key::P256(ref point) = JwtPublicKey::from_pem(EC_PUBLIC).unwrap().0 else {
+            panic!("expected a P-256 key");
+        };
+        let jwk = format!(
+            r#"{{"kty":"EC","crv":"P-256","x":"{}","y":"{}"}}"#,
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..])
+        );
+        assert_eq!(
+            JwtPublicKey::from_jwk(&jwk).unwrap(),
+            JwtPublicKey::from_pem(EC_PUBLIC).unwrap()
+        );
+
+        let Key::Rsa { ref n, ref e } = JwtPublicKey::from_pem(RSA_PUBLIC).unwrap().0 else {
+            panic!("expected an RSA key");
+        };
+        let jwk = format!(
+            r#"{{"kty":"RSA","n":"{}","e":"{}"}}"#,
+            URL_SAFE_NO_PAD.encode(n),
+            URL_SAFE_NO_PAD.encode(e)
+        );
+        assert_eq!(
+            JwtPublicKey::from_jwk(&jwk).unwrap(),
+            JwtPublicKey::from_pem(RSA_PUBLIC).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_jwks() {
+        assert!(JwtPublicKey::from_jwk(r#"{"kty":"oct","k":"c2VjcmV0"}"#).is_err());
+        assert!(JwtPublicKey::from_jwk(r#"{"kty":"EC","crv":"P-521","x":"AA","y":"AA"}"#).is_err());
+        assert!(JwtPublicKey::from_jwk(r#"{"kty":"EC","crv":"P-256","x":"AA","y":"AA"}"#).is_err());
+        assert!(JwtPublicKey::from_jwk(r#"{"kty":"RSA","n":"AQAB"}"#).is_err());
+    }
+}
@@ -4,13 +4,46 @@
 //! ingestion endpoint on a background thread. Includes exponential backoff,
 //! disk persistence for undelivered events, and SSRF protection.
 
+mod capture;
 mod client;
+mod client_ip;
 mod consumer;
+mod context;
+mod dead_letter;
+mod format;
+mod idempotency;
+mod jwt;
 pub mod middleware;
+mod query;
+mod request;
+mod response;
+mod signing;
 mod ssrf;
+mod tls;
+mod transport;
 mod types;
 
+pub use capture::{BodyCapture, CapturedHeader, HeaderCapture, HeaderRedaction};
 pub use client::PeekApiClient;
-pub use consumer::{default_identify_consumer, hash_consumer_id};
-pub use ssrf::{is_private_ip, validate_endpoint};
-pub use types::{ErrorCallback, IdentifyConsumerFn, Options, RequestEvent};
+pub use client_ip::{ClientIpOptions, ForwardingHeader, IpAnonymization};
+pub use consumer::{
+    default_identify_consumer, default_identify_consumer_with, hash_consumer_id,
+    hash_consumer_id_keyed, ApiKeyLookupFn, ApiKeyPolicy, ConsumerChain, ConsumerHashing,
+    ConsumerIdPolicy, IdEncoding, IdSource, JwtIdentifier,
+};
+pub use context::RequestContext;
+pub use dead_letter::{DeadLetter, DeadLetterReason};
+pub use format::PayloadFormat;
+pub use jwt::JwtPublicKey;
+pub use query::{normalize_query, QueryStringPolicy, QueryValueMode};
+pub use request::{ConsumerIdentity, PeerIdentity, RequestExtensions, RequestInfo, RequestView};
+pub use signing::{verify_signature, RequestSigning, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+pub use ssrf::{
+    is_private_ip, private_ip_reason, resolve_and_validate, validate_endpoint,
+    validate_endpoint_with, EndpointPolicy, Resolver, SystemResolver,
+};
+pub use tls::{PemSource, TlsOptions};
+pub use transport::{ProxyOptions, TransportOptions};
+pub use types::{
+    ApiKeyProviderFn, ErrorCallback, IdentifyConsumerFn, IdentifyRequestFn, Options, RequestEvent,
+};
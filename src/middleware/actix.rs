@@ -10,18 +10,30 @@
 //! let app = actix_web::App::new().wrap(PeekApi::new(client));
 //! ```
 
-use crate::consumer::default_identify_consumer;
-use crate::{PeekApiClient, RequestEvent};
+use super::body::{BodyCaptureState, PendingEvent, SharedTee};
+use crate::client_ip::join_header_lines;
+use crate::{
+    ConsumerIdentity, HeaderCapture, PeekApiClient, PeerIdentity, RequestContext, RequestEvent,
+    RequestExtensions, RequestView,
+};
 
 use actix_service::{Service, Transform};
-use actix_web::body::MessageBody;
-use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::Error;
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures_core::Stream;
+use pin_project_lite::pin_project;
 use std::future::{ready, Future, Ready};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Instant;
 
+type BoxedPayloadStream = Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>>;
+
 /// Actix Web middleware that captures request analytics.
 pub struct PeekApi {
     client: Arc<PeekApiClient>,
@@ -38,7 +50,7 @@ where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B, CaptureBody<B>>>;
     type Error = Error;
     type Transform = PeekApiMiddleware<S>;
     type InitError = ();
@@ -62,30 +74,20 @@ where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B, CaptureBody<B>>>;
     type Error = Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
-    fn poll_ready(
-        &self,
-        ctx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.service.poll_ready(ctx)
     }
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
         let start = Instant::now();
         let method = req.method().to_string();
-        let mut path = req.path().to_string();
-        if self.client.collect_query_string() {
-            let qs = req.query_string();
-            if !qs.is_empty() {
-                let mut params: Vec<&str> = qs.split('&').collect();
-                params.sort();
-                path.push('?');
-                path.push_str(&params.join("&"));
-            }
-        }
+        let path = self
+            .client
+            .tracked_path(req.path(), Some(req.query_string()));
         let request_size = req
             .headers()
             .get("content-length")
@@ -99,11 +101,31 @@ where
                 .and_then(|v| v.to_str().ok())
                 .map(|v| v.to_string())
         };
-        let consumer_id = if let Some(ref cb) = self.client.identify_consumer() {
-            cb(&get_header)
-        } else {
-            default_identify_consumer(get_header)
-        };
+        let request_headers = self
+            .client
+            .header_capture()
+            .map(|capture| capture.capture_request(get_header));
+        let context = RequestContext::new();
+        let client_ip = self
+            .client
+            .client_ip(req.peer_addr().map(|a| a.ip()), |name| {
+                join_header_lines(req.headers().get_all(name).filter_map(|v| v.to_str().ok()))
+            });
+        let body_capture =
+            BodyCaptureState::for_request(&self.client, req.path(), get_header("content-type"));
+
+        if let Some(tee) = body_capture
+            .as_ref()
+            .and_then(BodyCaptureState::request_tee)
+        {
+            let stream: BoxedPayloadStream = Box::pin(TeePayload {
+                inner: req.take_payload(),
+                tee,
+            });
+            req.set_payload(Payload::from(stream));
+        }
+
+        req.extensions_mut().insert(context.clone());
 
         let client = Arc::clone(&self.client);
         let fut = self.service.call(req);
@@ -113,6 +135,22 @@ where
 
             match result {
                 Ok(resp) => {
+                    // Identify after the inner services ran, so identities
+                    // inserted by auth middleware and extractors are visible
+                    let request = ActixRequest(resp.request());
+                    // A handler may also set the identity on the response
+                    let response_identity = resp
+                        .response()
+                        .extensions()
+                        .get::<ConsumerIdentity>()
+                        .filter(|id| !id.0.is_empty())
+                        .map(|id| id.0.clone());
+                    let consumer_id = response_identity.or_else(|| client.identify(&request));
+                    if let Some(legacy) =
+                        client.legacy_consumer_id(&request, consumer_id.as_deref())
+                    {
+                        context.set_metadata("consumer_id_legacy", legacy);
+                    }
                     let status = resp.status().as_u16();
                     let response_size = resp
                         .headers()
@@ -121,8 +159,15 @@ where
                         .and_then(|v| v.parse::<usize>().ok())
                         .unwrap_or(0);
 
+                    let get_header = |name: &str| {
+                        resp.headers()
+                            .get(name)
+                            .and_then(|v| v.to_str().ok())
+                            .map(|v| v.to_string())
+                    };
+
                     let elapsed = start.elapsed();
-                    client.track(RequestEvent {
+                    let mut event = RequestEvent {
                         method,
                         path,
                         status_code: status,
@@ -131,13 +176,162 @@ where
                         response_size,
                         consumer_id,
                         metadata: None,
+                        client_ip,
                         timestamp: String::new(),
-                    });
+                        event_id: String::new(),
+                    };
+                    if let Some(capture) = client.header_capture() {
+                        let response_headers = capture.capture_response(get_header);
+                        if let Some(headers) = HeaderCapture::to_metadata(
+                            request_headers.unwrap_or_default(),
+                            response_headers,
+                        ) {
+                            event.merge_metadata("headers", headers);
+                        }
+                    }
+
+                    if !context.apply(&mut event) {
+                        return Ok(resp.map_into_left_body());
+                    }
 
-                    Ok(resp)
+                    let Some(body_capture) = body_capture else {
+                        client.track(event);
+                        return Ok(resp.map_into_left_body());
+                    };
+                    let content_type = get_header("content-type");
+                    match body_capture.complete(client, event, content_type) {
+                        Some((pending, tee)) => Ok(resp
+                            .map_body(|_, body| CaptureBody {
+                                inner: body,
+                                tee,
+                                pending: Some(pending),
+                            })
+                            .map_into_right_body()),
+                        None => Ok(resp.map_into_left_body()),
+                    }
                 }
                 Err(e) => Err(e),
             }
         })
     }
 }
+
+/// Extracts the per-request [`RequestContext`] inserted by [`PeekApi`].
+///
+/// Outside the middleware a detached context is returned, so handlers work
+/// unchanged when analytics is disabled.
+impl FromRequest for RequestContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(req
+            .extensions()
+            .get::<RequestContext>()
+            .cloned()
+            .unwrap_or_default()))
+    }
+}
+
+/// [`RequestView`] over an Actix Web request.
+struct ActixRequest<'a>(&'a HttpRequest);
+
+impl RequestView for ActixRequest<'_> {
+    fn method(&self) -> &str {
+        self.0.method().as_str()
+    }
+
+    fn path(&self) -> &str {
+        self.0.path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        Some(self.0.query_string()).filter(|q| !q.is_empty())
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.0
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    fn consumer_identity(&self) -> Option<String> {
+        let extensions = self.0.extensions();
+        extensions.get::<ConsumerIdentity>().map(|id| id.0.clone())
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        let extensions = self.0.extensions();
+        extensions.get::<PeerIdentity>().map(|id| id.0.clone())
+    }
+
+    fn extensions(&self) -> RequestExtensions<'_> {
+        RequestExtensions::Actix(self.0.extensions())
+    }
+}
+
+pin_project! {
+    /// Response body wrapper used when body capture is enabled for a failed
+    /// request. Tees streamed chunks into the capture buffer and tracks the
+    /// event when the body ends or is dropped.
+    pub struct CaptureBody<B> {
+        #[pin]
+        inner: B,
+        tee: SharedTee,
+        pending: Option<PendingEvent>,
+    }
+}
+
+impl<B: MessageBody> MessageBody for CaptureBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => this.tee.lock().unwrap().push(chunk),
+            Poll::Ready(_) => {
+                if let Some(mut pending) = this.pending.take() {
+                    pending.finish();
+                }
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+pin_project! {
+    /// Request payload wrapper that tees streamed chunks into the capture buffer.
+    struct TeePayload {
+        #[pin]
+        inner: Payload,
+        tee: SharedTee,
+    }
+}
+
+impl Stream for TeePayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let poll = this.inner.poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+            this.tee.lock().unwrap().push(chunk);
+        }
+        poll
+    }
+}
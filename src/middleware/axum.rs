@@ -11,13 +11,21 @@
 //! let app = Router::new().layer(PeekApiLayer::new(client));
 //! ```
 
-use crate::consumer::default_identify_consumer;
-use crate::{PeekApiClient, RequestEvent};
+use super::body::{BodyCaptureState, PendingEvent, SharedTee};
+use crate::client_ip::join_header_lines;
+use crate::{
+    ConsumerIdentity, HeaderCapture, PeekApiClient, PeerIdentity, RequestContext, RequestEvent,
+    RequestExtensions, RequestView,
+};
 
-use axum::body::Body;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::ConnectInfo;
 use http::Request;
+use http_body::{Frame, SizeHint};
 use pin_project_lite::pin_project;
+use serde_json::{Map, Value};
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -71,17 +79,9 @@ where
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let start = Instant::now();
         let method = req.method().to_string();
-        let mut path = req.uri().path().to_string();
-        if self.client.collect_query_string() {
-            if let Some(qs) = req.uri().query() {
-                if !qs.is_empty() {
-                    let mut params: Vec<&str> = qs.split('&').collect();
-                    params.sort();
-                    path.push('?');
-                    path.push_str(&params.join("&"));
-                }
-            }
-        }
+        let path = self
+            .client
+            .tracked_path(req.uri().path(), req.uri().query());
         let request_size = req
             .headers()
             .get("content-length")
@@ -95,12 +95,39 @@ where
                 .and_then(|v| v.to_str().ok())
                 .map(|v| v.to_string())
         };
-        let consumer_id = if let Some(ref cb) = self.client.identify_consumer() {
-            cb(&get_header)
-        } else {
-            default_identify_consumer(get_header)
-        };
+        let request_headers = self
+            .client
+            .header_capture()
+            .map(|capture| capture.capture_request(get_header));
+        let request = AxumRequest(&req);
+        let consumer_id = self.client.identify(&request);
+        // Compared with the final consumer ID once the response is known
+        let legacy_consumer_id = self.client.legacy_consumer_id(&request, None);
+        let context = RequestContext::new();
+        let client_ip = self
+            .client
+            .client_ip(request.peer_addr().map(|a| a.ip()), |name| {
+                join_header_lines(
+                    req.headers()
+                        .get_all(name)
+                        .iter()
+                        .filter_map(|v| v.to_str().ok()),
+                )
+            });
+        let body_capture = BodyCaptureState::for_request(
+            &self.client,
+            req.uri().path(),
+            get_header("content-type"),
+        );
 
+        let mut req = match body_capture
+            .as_ref()
+            .and_then(BodyCaptureState::request_tee)
+        {
+            Some(tee) => req.map(|body| Body::new(TeeBody::new(body, tee, None))),
+            None => req,
+        };
+        req.extensions_mut().insert(context.clone());
         let future = self.inner.call(req);
 
         ResponseFuture {
@@ -111,6 +138,11 @@ where
             path,
             request_size,
             consumer_id,
+            legacy_consumer_id,
+            request_headers,
+            client_ip,
+            body_capture,
+            context,
         }
     }
 }
@@ -126,6 +158,11 @@ pin_project! {
         path: String,
         request_size: usize,
         consumer_id: Option<String>,
+        legacy_consumer_id: Option<String>,
+        request_headers: Option<Map<String, Value>>,
+        client_ip: Option<String>,
+        body_capture: Option<BodyCaptureState>,
+        context: RequestContext,
     }
 }
 
@@ -139,31 +176,182 @@ where
         let this = self.project();
         match this.inner.poll(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(result) => {
-                if let Ok(ref resp) = result {
-                    let status = resp.status().as_u16();
-                    let response_size = resp
-                        .headers()
-                        .get("content-length")
+            Poll::Ready(result) => Poll::Ready(result.map(|resp| {
+                // Handlers and inner layers can't reach the request this
+                // layer identified; they set the identity on the response
+                if let Some(id) = resp
+                    .extensions()
+                    .get::<ConsumerIdentity>()
+                    .filter(|id| !id.0.is_empty())
+                {
+                    *this.consumer_id = Some(id.0.clone());
+                }
+                if let Some(legacy) = this
+                    .legacy_consumer_id
+                    .take()
+                    .filter(|legacy| this.consumer_id.as_ref() != Some(legacy))
+                {
+                    this.context.set_metadata("consumer_id_legacy", legacy);
+                }
+                let status = resp.status().as_u16();
+                let response_size = resp
+                    .headers()
+                    .get("content-length")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(0);
+                let get_header = |name: &str| {
+                    resp.headers()
+                        .get(name)
                         .and_then(|v| v.to_str().ok())
-                        .and_then(|v| v.parse::<usize>().ok())
-                        .unwrap_or(0);
-
-                    let elapsed = this.start.elapsed();
-                    this.client.track(RequestEvent {
-                        method: std::mem::take(this.method),
-                        path: std::mem::take(this.path),
-                        status_code: status,
-                        response_time_ms: elapsed.as_secs_f64() * 1000.0,
-                        request_size: *this.request_size,
-                        response_size,
-                        consumer_id: this.consumer_id.take(),
-                        metadata: None,
-                        timestamp: String::new(),
-                    });
+                        .map(|v| v.to_string())
+                };
+
+                let elapsed = this.start.elapsed();
+                let mut event = RequestEvent {
+                    method: std::mem::take(this.method),
+                    path: std::mem::take(this.path),
+                    status_code: status,
+                    response_time_ms: elapsed.as_secs_f64() * 1000.0,
+                    request_size: *this.request_size,
+                    response_size,
+                    consumer_id: this.consumer_id.take(),
+                    metadata: None,
+                    client_ip: this.client_ip.take(),
+                    timestamp: String::new(),
+                    event_id: String::new(),
+                };
+                if let Some(capture) = this.client.header_capture() {
+                    let response_headers = capture.capture_response(get_header);
+                    if let Some(headers) = HeaderCapture::to_metadata(
+                        this.request_headers.take().unwrap_or_default(),
+                        response_headers,
+                    ) {
+                        event.merge_metadata("headers", headers);
+                    }
+                }
+
+                if !this.context.apply(&mut event) {
+                    return resp;
+                }
+
+                let Some(body_capture) = this.body_capture.take() else {
+                    this.client.track(event);
+                    return resp;
+                };
+                let content_type = get_header("content-type");
+                match body_capture.complete(Arc::clone(this.client), event, content_type) {
+                    Some((pending, tee)) => {
+                        resp.map(|body| Body::new(TeeBody::new(body, tee, Some(pending))))
+                    }
+                    None => resp,
+                }
+            })),
+        }
+    }
+}
+
+/// [`RequestView`] over an Axum request. Identities are read from request
+/// extensions, so they must be inserted by layers outside `PeekApiLayer`;
+/// inner layers and handlers set a [`ConsumerIdentity`] on the response.
+struct AxumRequest<'a>(&'a Request<Body>);
+
+impl RequestView for AxumRequest<'_> {
+    fn method(&self) -> &str {
+        self.0.method().as_str()
+    }
+
+    fn path(&self) -> &str {
+        self.0.uri().path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.0.uri().query()
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.0
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0)
+    }
+
+    fn consumer_identity(&self) -> Option<String> {
+        self.0
+            .extensions()
+            .get::<ConsumerIdentity>()
+            .map(|id| id.0.clone())
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.0
+            .extensions()
+            .get::<PeerIdentity>()
+            .map(|id| id.0.clone())
+    }
+
+    fn extensions(&self) -> RequestExtensions<'_> {
+        RequestExtensions::Http(self.0.extensions())
+    }
+}
+
+/// Body wrapper that tees streamed data into a capture buffer and tracks the
+/// pending event (if any) when the stream ends.
+struct TeeBody {
+    inner: Body,
+    tee: SharedTee,
+    pending: Option<PendingEvent>,
+}
+
+impl TeeBody {
+    fn new(inner: Body, tee: SharedTee, pending: Option<PendingEvent>) -> Self {
+        Self {
+            inner,
+            tee,
+            pending,
+        }
+    }
+}
+
+impl HttpBody for TeeBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.tee.lock().unwrap().push(data);
                 }
-                Poll::Ready(result)
             }
+            Poll::Ready(_) => {
+                if let Some(mut pending) = this.pending.take() {
+                    pending.finish();
+                }
+            }
+            Poll::Pending => {}
         }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
     }
 }
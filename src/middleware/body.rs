@@ -0,0 +1,179 @@
+//! Body tee shared by the Actix Web and Axum adapters.
+//!
+//! Bodies are copied (up to a limit) while they stream through; the event is
+//! tracked once the response body finishes or is dropped.
+
+use crate::{BodyCapture, PeekApiClient, RequestEvent};
+
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex};
+
+/// Bounded copy of a streamed body.
+pub(crate) struct TeeBuffer {
+    data: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+}
+
+pub(crate) type SharedTee = Arc<Mutex<TeeBuffer>>;
+
+impl TeeBuffer {
+    pub(crate) fn shared(limit: usize) -> SharedTee {
+        Arc::new(Mutex::new(Self {
+            data: Vec::new(),
+            limit,
+            truncated: false,
+        }))
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        let room = self.limit.saturating_sub(self.data.len());
+        if chunk.len() > room {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+}
+
+/// A teed body together with its content type.
+pub(crate) struct CapturedBody {
+    content_type: String,
+    tee: SharedTee,
+}
+
+/// Per-request capture state, created when body capture is enabled for the route.
+pub(crate) struct BodyCaptureState {
+    request: Option<CapturedBody>,
+    capture_response: bool,
+    min_status: u16,
+    max_bytes: usize,
+}
+
+impl BodyCaptureState {
+    /// Returns `None` when body capture is disabled or does not match `path`.
+    pub(crate) fn for_request(
+        client: &PeekApiClient,
+        path: &str,
+        content_type: Option<String>,
+    ) -> Option<Self> {
+        let capture = client.body_capture().filter(|c| c.matches_route(path))?;
+        let request = content_type
+            .filter(|ct| capture.request && BodyCapture::is_capturable(ct))
+            .map(|content_type| CapturedBody {
+                content_type,
+                tee: TeeBuffer::shared(capture.max_bytes),
+            });
+        Some(Self {
+            request,
+            capture_response: capture.response,
+            min_status: capture.min_status,
+            max_bytes: capture.max_bytes,
+        })
+    }
+
+    /// Tee for the request body, if it should be captured.
+    pub(crate) fn request_tee(&self) -> Option<SharedTee> {
+        self.request.as_ref().map(|body| Arc::clone(&body.tee))
+    }
+
+    /// Hand off the event once the response head is available.
+    ///
+    /// Tracks the event immediately unless the response body needs to be
+    /// teed, in which case the pending event and the response tee are
+    /// returned for the adapter to wrap the body with.
+    pub(crate) fn complete(
+        self,
+        client: Arc<PeekApiClient>,
+        event: RequestEvent,
+        content_type: Option<String>,
+    ) -> Option<(PendingEvent, SharedTee)> {
+        if event.status_code < self.min_status {
+            client.track(event);
+            return None;
+        }
+        let response = content_type
+            .filter(|ct| self.capture_response && BodyCapture::is_capturable(ct))
+            .map(|content_type| CapturedBody {
+                content_type,
+                tee: TeeBuffer::shared(self.max_bytes),
+            });
+        let tee = response.as_ref().map(|body| Arc::clone(&body.tee));
+        let pending = PendingEvent::new(client, event, self.request, response);
+        // Without a response tee, dropping `pending` tracks the event now
+        tee.map(|tee| (pending, tee))
+    }
+}
+
+/// Event waiting for the response body to finish streaming.
+///
+/// Tracked on drop, so the event is recorded even if the client disconnects
+/// before the body completes.
+pub(crate) struct PendingEvent {
+    client: Arc<PeekApiClient>,
+    event: Option<RequestEvent>,
+    request: Option<CapturedBody>,
+    response: Option<CapturedBody>,
+}
+
+impl PendingEvent {
+    fn new(
+        client: Arc<PeekApiClient>,
+        event: RequestEvent,
+        request: Option<CapturedBody>,
+        response: Option<CapturedBody>,
+    ) -> Self {
+        Self {
+            client,
+            event: Some(event),
+            request,
+            response,
+        }
+    }
+
+    /// Attach captured bodies and track the event. Idempotent.
+    pub(crate) fn finish(&mut self) {
+        let Some(mut event) = self.event.take() else {
+            return;
+        };
+        if let Some(capture) = self.client.body_capture() {
+            let mut bodies = Map::new();
+            for (key, body) in [("request", &self.request), ("response", &self.response)] {
+                let Some(body) = body else { continue };
+                let tee = body.tee.lock().unwrap();
+                if tee.data.is_empty() {
+                    continue;
+                }
+                bodies.insert(
+                    key.to_string(),
+                    capture.render(&body.content_type, &tee.data, tee.truncated),
+                );
+            }
+            if !bodies.is_empty() {
+                event.merge_metadata("body", Value::Object(bodies));
+            }
+        }
+        self.client.track(event);
+    }
+}
+
+impl Drop for PendingEvent {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tee_buffer_caps_and_flags_truncation() {
+        let tee = TeeBuffer::shared(5);
+        let mut buf = tee.lock().unwrap();
+        buf.push(b"abc");
+        assert!(!buf.truncated);
+        buf.push(b"defg");
+        assert_eq!(buf.data, b"abcde");
+        assert!(buf.truncated);
+    }
+}
@@ -11,3 +11,6 @@ pub mod axum;
 
 #[cfg(feature = "rocket-fairing")]
 pub mod rocket;
+
+#[cfg(any(feature = "actix", feature = "axum-middleware"))]
+mod body;
@@ -10,11 +10,16 @@
 //! let rocket = rocket::build().attach(PeekApiFairing::new(client));
 //! ```
 
-use crate::consumer::default_identify_consumer;
-use crate::{PeekApiClient, RequestEvent};
+use crate::client_ip::join_header_lines;
+use crate::{
+    ConsumerIdentity, HeaderCapture, PeekApiClient, PeerIdentity, RequestContext, RequestEvent,
+    RequestView,
+};
 
 use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{FromRequest, Outcome};
 use rocket::{Data, Request, Response};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -39,8 +44,9 @@ impl Fairing for PeekApiFairing {
     }
 
     async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
-        // Store the start time in local cache
+        // Store the start time and per-request context in local cache
         req.local_cache(Instant::now);
+        req.local_cache(RequestContext::new);
     }
 
     async fn on_response<'r>(&self, req: &'r Request<'_>, resp: &mut Response<'r>) {
@@ -48,18 +54,10 @@ impl Fairing for PeekApiFairing {
         let elapsed = start.elapsed();
 
         let method = req.method().as_str().to_string();
-        let mut path = req.uri().path().to_string();
-        if self.client.collect_query_string() {
-            if let Some(qs) = req.uri().query() {
-                let qs_str = qs.as_str();
-                if !qs_str.is_empty() {
-                    let mut params: Vec<&str> = qs_str.split('&').collect();
-                    params.sort();
-                    path.push('?');
-                    path.push_str(&params.join("&"));
-                }
-            }
-        }
+        let path = self.client.tracked_path(
+            req.uri().path().as_str(),
+            req.uri().query().map(|q| q.as_str()),
+        );
         let status = resp.status().code;
 
         let request_size = req
@@ -71,13 +69,10 @@ impl Fairing for PeekApiFairing {
         let response_size = resp.body().preset_size().unwrap_or(0);
 
         let get_header = |name: &str| req.headers().get_one(name).map(|v| v.to_string());
-        let consumer_id = if let Some(ref cb) = self.client.identify_consumer() {
-            cb(&get_header)
-        } else {
-            default_identify_consumer(get_header)
-        };
+        let request = RocketRequest(req);
+        let consumer_id = self.client.identify(&request);
 
-        self.client.track(RequestEvent {
+        let mut event = RequestEvent {
             method,
             path,
             status_code: status,
@@ -86,7 +81,104 @@ impl Fairing for PeekApiFairing {
             response_size,
             consumer_id,
             metadata: None,
+            client_ip: self.client.client_ip(req.remote().map(|r| r.ip()), |name| {
+                join_header_lines(req.headers().get(name))
+            }),
             timestamp: String::new(),
-        });
+            event_id: String::new(),
+        };
+        if let Some(capture) = self.client.header_capture() {
+            let request_headers = capture.capture_request(get_header);
+            let response_headers = capture
+                .capture_response(|name| resp.headers().get_one(name).map(|v| v.to_string()));
+            if let Some(headers) = HeaderCapture::to_metadata(request_headers, response_headers) {
+                event.merge_metadata("headers", headers);
+            }
+        }
+        let context = req.local_cache(RequestContext::new);
+        if let Some(legacy) = self
+            .client
+            .legacy_consumer_id(&request, event.consumer_id.as_deref())
+        {
+            context.set_metadata("consumer_id_legacy", legacy);
+        }
+        if context.apply(&mut event) {
+            self.client.track(event);
+        }
+    }
+}
+
+/// [`RequestView`] over a Rocket request. Identities are read from the
+/// request-local cache, where guards can set them; an empty cached value
+/// means none was set.
+struct RocketRequest<'a, 'r>(&'a Request<'r>);
+
+impl RequestView for RocketRequest<'_, '_> {
+    fn method(&self) -> &str {
+        self.0.method().as_str()
+    }
+
+    fn path(&self) -> &str {
+        self.0.uri().path().as_str()
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.0.uri().query().map(|q| q.as_str())
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.0.headers().get_one(name).map(|v| v.to_string())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.remote()
+    }
+
+    fn consumer_identity(&self) -> Option<String> {
+        let id = &self.0.local_cache(ConsumerIdentity::default).0;
+        (!id.is_empty()).then(|| id.clone())
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        let id = &self.0.local_cache(PeerIdentity::default).0;
+        (!id.is_empty()).then(|| id.clone())
+    }
+}
+
+/// Request guard for the per-request [`RequestContext`].
+///
+/// Without the fairing attached, a detached context is returned.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestContext {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(RequestContext::new).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    #[test]
+    fn identities_are_none_until_set() {
+        let client = Client::untracked(rocket::build()).unwrap();
+        let local = client.get("/");
+        let view = RocketRequest(local.inner());
+        assert_eq!(view.consumer_identity(), None);
+        assert_eq!(view.peer_identity(), None);
+
+        let local = client.get("/");
+        local
+            .inner()
+            .local_cache(|| ConsumerIdentity("acct_42".to_string()));
+        local
+            .inner()
+            .local_cache(|| PeerIdentity("CN=ingest-client".to_string()));
+        let view = RocketRequest(local.inner());
+        assert_eq!(view.consumer_identity().as_deref(), Some("acct_42"));
+        assert_eq!(view.peer_identity().as_deref(), Some("CN=ingest-client"));
     }
 }
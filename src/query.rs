@@ -0,0 +1,124 @@
+/// How query parameter values are recorded in the tracked path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueryValueMode {
+    /// Keep values verbatim (`?page=2&sort=name`).
+    #[default]
+    Keep,
+    /// Keep parameter names, drop values (`?page=&sort=`).
+    Strip,
+    /// Replace non-negative integer values with an order-of-magnitude bucket
+    /// (`?limit=10-99`). Non-numeric values are kept as-is.
+    BucketNumeric,
+}
+
+/// Controls which query parameters end up in the tracked path.
+///
+/// Each unique normalized path+query creates a separate endpoint row, so
+/// prefer an allow-list and value stripping over collecting everything.
+#[derive(Debug, Clone, Default)]
+pub struct QueryStringPolicy {
+    /// Parameter names to keep. `None` keeps every parameter.
+    pub allowed_params: Option<Vec<String>>,
+    /// How parameter values are recorded.
+    pub values: QueryValueMode,
+}
+
+impl QueryStringPolicy {
+    /// Keep only the listed parameter names.
+    pub fn allow<I, S>(params: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            allowed_params: Some(params.into_iter().map(Into::into).collect()),
+            values: QueryValueMode::Keep,
+        }
+    }
+
+    /// Set how parameter values are recorded.
+    pub fn values(mut self, mode: QueryValueMode) -> Self {
+        self.values = mode;
+        self
+    }
+}
+
+/// Normalize a raw query string according to `policy`.
+///
+/// Parameters are filtered by the allow-list, their values rewritten per
+/// [`QueryValueMode`], and the result sorted so that parameter order does
+/// not create distinct endpoints. Returns an empty string when nothing is kept.
+pub fn normalize_query(qs: &str, policy: &QueryStringPolicy) -> String {
+    let mut params: Vec<String> = qs
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .filter_map(|p| {
+            let (name, value) = match p.split_once('=') {
+                Some((n, v)) => (n, Some(v)),
+                None => (p, None),
+            };
+            if let Some(ref allowed) = policy.allowed_params {
+                if !allowed.iter().any(|a| a == name) {
+                    return None;
+                }
+            }
+            Some(match (policy.values, value) {
+                (QueryValueMode::Keep, _) => p.to_string(),
+                (QueryValueMode::Strip, _) => format!("{name}="),
+                (QueryValueMode::BucketNumeric, Some(v)) => format!("{name}={}", bucket_value(v)),
+                (QueryValueMode::BucketNumeric, None) => name.to_string(),
+            })
+        })
+        .collect();
+    params.sort();
+    params.join("&")
+}
+
+fn bucket_value(value: &str) -> String {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return value.to_string();
+    }
+    let digits = value.trim_start_matches('0');
+    if digits.is_empty() {
+        return "0".to_string();
+    }
+    let lower = format!("1{}", "0".repeat(digits.len() - 1));
+    let upper = "9".repeat(digits.len());
+    format!("{lower}-{upper}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_and_sorts_all_params_by_default() {
+        let policy = QueryStringPolicy::default();
+        assert_eq!(normalize_query("z=1&a=2", &policy), "a=2&z=1");
+    }
+
+    #[test]
+    fn allow_list_drops_unlisted_params() {
+        let policy = QueryStringPolicy::allow(["page", "sort"]);
+        assert_eq!(
+            normalize_query("sort=name&token=abc&page=2", &policy),
+            "page=2&sort=name"
+        );
+        assert_eq!(normalize_query("token=abc", &policy), "");
+    }
+
+    #[test]
+    fn strip_keeps_names_only() {
+        let policy = QueryStringPolicy::default().values(QueryValueMode::Strip);
+        assert_eq!(normalize_query("sort=name&page=2", &policy), "page=&sort=");
+    }
+
+    #[test]
+    fn bucket_numeric_values() {
+        let policy = QueryStringPolicy::default().values(QueryValueMode::BucketNumeric);
+        assert_eq!(
+            normalize_query("limit=25&offset=0&q=abc&page=7&id=1234", &policy),
+            "id=1000-9999&limit=10-99&offset=0&page=1-9&q=abc"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::any::Any;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Read-only view of an incoming request, implemented by each middleware
+/// adapter and passed to consumer identifiers.
+pub trait RequestView {
+    fn method(&self) -> &str;
+
+    /// Request path, without the query string.
+    fn path(&self) -> &str;
+
+    /// Raw query string, if any.
+    fn query(&self) -> Option<&str>;
+
+    /// Header value by lowercase name. Non-UTF-8 values are treated as absent.
+    fn header(&self, name: &str) -> Option<String>;
+
+    /// Cookie value from the `Cookie` header.
+    fn cookie(&self, name: &str) -> Option<String> {
+        self.header("cookie")?.split(';').find_map(|pair| {
+            let (k, v) = pair.trim().split_once('=')?;
+            (k == name).then(|| v.trim_matches('"').to_string())
+        })
+    }
+
+    /// Address of the connected peer (not forwarding headers).
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// [`ConsumerIdentity`] set by the application's auth layer, if any.
+    fn consumer_identity(&self) -> Option<String>;
+
+    /// [`PeerIdentity`] set by the application's TLS layer, if any.
+    fn peer_identity(&self) -> Option<String>;
+
+    /// Typed values stored on the request by other middleware, such as the
+    /// claims an auth layer decoded. Rocket has no such map: use its
+    /// request-local cache with [`ConsumerIdentity`] instead.
+    fn extensions(&self) -> RequestExtensions<'_> {
+        RequestExtensions::None
+    }
+}
+
+/// The request's extension map, as exposed by [`RequestView::extensions`].
+///
+/// ```rust
+/// use peekapi::{RequestInfo, RequestView};
+///
+/// // Inserted by the application's auth middleware
+/// struct Claims {
+///     sub: String,
+/// }
+///
+/// let req = RequestInfo::new("GET", "/").with_extension(Claims { sub: "user_1".into() });
+/// let sub = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+/// assert_eq!(sub.as_deref(), Some("user_1"));
+/// ```
+pub enum RequestExtensions<'a> {
+    /// No extension map.
+    None,
+    /// Values attached to a [`RequestInfo`].
+    Owned(&'a [Arc<dyn Any + Send + Sync>]),
+    /// Axum request extensions.
+    #[cfg(feature = "axum-middleware")]
+    Http(&'a http::Extensions),
+    /// Actix Web request extensions.
+    #[cfg(feature = "actix")]
+    Actix(std::cell::Ref<'a, actix_web::dev::Extensions>),
+}
+
+impl RequestExtensions<'_> {
+    /// The stored value of type `T`, if any.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        match self {
+            Self::None => None,
+            Self::Owned(values) => values.iter().find_map(|v| v.downcast_ref()),
+            #[cfg(feature = "axum-middleware")]
+            Self::Http(extensions) => extensions.get(),
+            #[cfg(feature = "actix")]
+            Self::Actix(extensions) => extensions.get(),
+        }
+    }
+}
+
+/// Consumer ID established by framework-level authentication.
+///
+/// Insert it where the adapter can see it and it takes priority over header
+/// identification:
+///
+///   - Actix Web: `req.extensions_mut().insert(ConsumerIdentity::new(id))` in
+///     middleware, an extractor or the handler
+///   - Axum: `req.extensions_mut().insert(ConsumerIdentity::new(id))` in a
+///     layer outside `PeekApiLayer`. Layers inside it, extractors and handlers
+///     get the request after it was identified, so they insert it into the
+///     response extensions instead (e.g. return `Extension(identity)`).
+///   - Rocket: `req.local_cache(|| ConsumerIdentity::new(id))` in a request guard
+///
+/// In Actix Web and Axum, an identity in the response extensions replaces
+/// the consumer identified from the request. An empty identity is treated
+/// as unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsumerIdentity(pub String);
+
+impl ConsumerIdentity {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+}
+
+/// Identity of a verified client certificate (e.g. its subject DN or SPIFFE
+/// ID), inserted the same way as [`ConsumerIdentity`] by a TLS acceptor that
+/// terminates mTLS in-process.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerIdentity(pub String);
+
+impl PeerIdentity {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+}
+
+/// Owned [`RequestView`] for identifying consumers outside the middleware
+/// adapters, and for tests.
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    /// `(name, value)` pairs; names are matched case-insensitively.
+    pub headers: Vec<(String, String)>,
+    pub peer_addr: Option<SocketAddr>,
+    pub consumer_identity: Option<String>,
+    pub peer_identity: Option<String>,
+    /// Values returned by [`RequestView::extensions`].
+    pub extensions: Vec<Arc<dyn Any + Send + Sync>>,
+}
+
+impl RequestInfo {
+    pub fn new(method: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            method: method.into(),
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Add a header.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add an extension value, replacing any of the same type.
+    pub fn with_extension<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.extensions.retain(|v| !v.is::<T>());
+        self.extensions.push(Arc::new(value));
+        self
+    }
+}
+
+impl RequestView for RequestInfo {
+    fn method(&self) -> &str {
+        &self.method
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    fn consumer_identity(&self) -> Option<String> {
+        self.consumer_identity.clone()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.peer_identity.clone()
+    }
+
+    fn extensions(&self) -> RequestExtensions<'_> {
+        RequestExtensions::Owned(&self.extensions)
+    }
+}
@@ -0,0 +1,166 @@
+use crate::types::RequestEvent;
+
+use serde::Deserialize;
+
+/// An event the ingestion API refused, identified by its index in the batch.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct Rejection {
+    pub index: usize,
+    #[serde(default)]
+    pub reason: String,
+    /// Transient failures (e.g. a storage timeout) are sent again; all
+    /// others are dropped.
+    #[serde(default)]
+    pub retryable: bool,
+}
+
+#[derive(Deserialize)]
+struct IngestResponse {
+    rejected: Option<Vec<Rejection>>,
+}
+
+/// Per-event results of a request the ingestion API answered.
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    /// Rejected events, sorted by index.
+    pub rejected: Vec<Rejection>,
+    /// Whether the events not listed in `rejected` were stored. False for a
+    /// 4xx response refusing individual events, whose other events must be
+    /// sent again.
+    pub stored: bool,
+}
+
+impl Delivery {
+    /// The whole batch was stored.
+    pub fn stored() -> Self {
+        Self {
+            rejected: Vec::new(),
+            stored: true,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.stored && self.rejected.is_empty()
+    }
+
+    /// Split `events` into permanently rejected events (with the reason) and
+    /// events to send again. Stored events are dropped.
+    pub fn split(
+        self,
+        events: Vec<RequestEvent>,
+    ) -> (Vec<(RequestEvent, String)>, Vec<RequestEvent>) {
+        let mut dropped = Vec::new();
+        let mut retry = Vec::new();
+        for (index, event) in events.into_iter().enumerate() {
+            match self.rejected.binary_search_by_key(&index, |r| r.index) {
+                Ok(i) if !self.rejected[i].retryable => {
+                    dropped.push((event, self.rejected[i].reason.clone()));
+                }
+                Ok(_) => retry.push(event),
+                Err(_) if !self.stored => retry.push(event),
+                Err(_) => {}
+            }
+        }
+        (dropped, retry)
+    }
+}
+
+/// Parse per-event rejections from an ingestion response body:
+///
+/// ```json
+/// {"rejected": [{"index": 3, "reason": "invalid status_code", "retryable": false}]}
+/// ```
+///
+/// Returns `None` when the body has no `rejected` list. Out-of-range and
+/// duplicate indices are ignored.
+pub(crate) fn parse_rejections(body: &[u8], batch_len: usize) -> Option<Vec<Rejection>> {
+    let response: IngestResponse = serde_json::from_slice(body).ok()?;
+    let mut rejected = response.rejected?;
+    rejected.retain(|r| r.index < batch_len);
+    rejected.sort_by_key(|r| r.index);
+    rejected.dedup_by_key(|r| r.index);
+    Some(rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(n: usize) -> Vec<RequestEvent> {
+        (0..n)
+            .map(|i| RequestEvent {
+                path: format!("/{i}"),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn paths(events: &[RequestEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_rejected_indices() {
+        let body = br#"{"accepted": 2, "rejected": [
+            {"index": 2, "reason": "timeout", "retryable": true},
+            {"index": 0, "reason": "invalid path"},
+            {"index": 0, "reason": "duplicate"},
+            {"index": 9, "reason": "out of range"}
+        ]}"#;
+        let rejected = parse_rejections(body, 4).unwrap();
+        assert_eq!(
+            rejected,
+            [
+                Rejection {
+                    index: 0,
+                    reason: "invalid path".to_string(),
+                    retryable: false,
+                },
+                Rejection {
+                    index: 2,
+                    reason: "timeout".to_string(),
+                    retryable: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bodies_without_rejections_are_ignored() {
+        assert!(parse_rejections(b"", 1).is_none());
+        assert!(parse_rejections(b"ok", 1).is_none());
+        assert!(parse_rejections(br#"{"accepted": 1}"#, 1).is_none());
+        assert!(parse_rejections(br#"{"rejected": [{"reason": "x"}]}"#, 1).is_none());
+        assert_eq!(parse_rejections(br#"{"rejected": []}"#, 1), Some(vec![]));
+    }
+
+    #[test]
+    fn split_drops_stored_and_permanent_events() {
+        let rejected = parse_rejections(
+            br#"{"rejected": [{"index": 1, "reason": "bad"}, {"index": 2, "retryable": true}]}"#,
+            4,
+        )
+        .unwrap();
+
+        let delivery = Delivery {
+            rejected: rejected.clone(),
+            stored: true,
+        };
+        let (dropped, retry) = delivery.split(events(4));
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(
+            (dropped[0].0.path.as_str(), dropped[0].1.as_str()),
+            ("/1", "bad")
+        );
+        assert_eq!(paths(&retry), ["/2"]);
+
+        // Nothing was stored: everything but the permanent rejection is resent
+        let delivery = Delivery {
+            rejected,
+            stored: false,
+        };
+        let (dropped, retry) = delivery.split(events(4));
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(paths(&retry), ["/0", "/2", "/3"]);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::consumer::hex;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header carrying the signing time, in Unix seconds.
+pub const TIMESTAMP_HEADER: &str = "x-peekapi-timestamp";
+/// Header carrying the payload signature, `v1=<hex HMAC-SHA256>`.
+pub const SIGNATURE_HEADER: &str = "x-peekapi-signature";
+
+/// HMAC-SHA256 signing of ingestion requests.
+///
+/// The signature covers `"<timestamp>.<body>"`, so a captured request is
+/// only accepted within the receiver's tolerance window. Verify it with
+/// [`verify_signature`].
+#[derive(Clone)]
+pub struct RequestSigning {
+    /// Shared secret, also configured on the ingestion side.
+    pub secret: Vec<u8>,
+}
+
+impl RequestSigning {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// Signature header value for `body` signed at `timestamp`.
+    pub fn sign(&self, timestamp: u64, body: &[u8]) -> String {
+        let digest = mac(&self.secret, timestamp, body).finalize().into_bytes();
+        format!("v1={}", hex::encode(&digest))
+    }
+
+    /// `(timestamp, signature)` header values for `body`, signed now.
+    pub(crate) fn headers(&self, body: &[u8]) -> (String, String) {
+        let timestamp = unix_now();
+        (timestamp.to_string(), self.sign(timestamp, body))
+    }
+}
+
+impl std::fmt::Debug for RequestSigning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestSigning")
+            .field("secret", &"..")
+            .finish()
+    }
+}
+
+/// Verify the signature headers of an ingestion request.
+///
+/// `timestamp` and `signature` are the raw [`TIMESTAMP_HEADER`] and
+/// [`SIGNATURE_HEADER`] values and `body` the raw request body. Requests
+/// signed more than `tolerance` away from the current time are rejected.
+/// The signature may list several comma-separated `v1=` values (e.g.
+/// during a secret rotation); one match is enough.
+pub fn verify_signature(
+    secret: &[u8],
+    timestamp: &str,
+    signature: &str,
+    body: &[u8],
+    tolerance: Duration,
+) -> Result<(), String> {
+    verify_signature_at(secret, timestamp, signature, body, tolerance, unix_now())
+}
+
+fn verify_signature_at(
+    secret: &[u8],
+    timestamp: &str,
+    signature: &str,
+    body: &[u8],
+    tolerance: Duration,
+    now: u64,
+) -> Result<(), String> {
+    let ts: u64 = timestamp
+        .trim()
+        .parse()
+        .map_err(|_| "[peekapi] Invalid signature timestamp".to_string())?;
+    if ts.abs_diff(now) > tolerance.as_secs() {
+        return Err("[peekapi] Signature timestamp outside tolerance".to_string());
+    }
+    let matched = signature
+        .split(',')
+        .filter_map(|part| part.trim().strip_prefix("v1="))
+        .filter_map(decode_hex)
+        .any(|candidate| mac(secret, ts, body).verify_slice(&candidate).is_ok());
+    if matched {
+        Ok(())
+    } else {
+        Err("[peekapi] Signature mismatch".to_string())
+    }
+}
+
+fn mac(secret: &[u8], timestamp: u64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"whsec_test";
+    const BODY: &[u8] = br#"[{"method":"GET"}]"#;
+    const TOLERANCE: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn sign_is_stable_and_keyed() {
+        let signing = RequestSigning::new(SECRET);
+        let sig = signing.sign(1_700_000_000, BODY);
+        assert!(sig.starts_with("v1="));
+        assert_eq!(sig.len(), 3 + 64);
+        assert_eq!(sig, signing.sign(1_700_000_000, BODY));
+        assert_ne!(sig, signing.sign(1_700_000_001, BODY));
+        assert_ne!(sig, RequestSigning::new("other").sign(1_700_000_000, BODY));
+    }
+
+    #[test]
+    fn verify_accepts_valid_signatures() {
+        let sig = RequestSigning::new(SECRET).sign(1_700_000_000, BODY);
+        let verify =
+            |sig: &str, now| verify_signature_at(SECRET, "1700000000", sig, BODY, TOLERANCE, now);
+        assert!(verify(&sig, 1_700_000_000).is_ok());
+        assert!(verify(&sig, 1_700_000_300).is_ok());
+        assert!(verify(&format!("v1=00ff, {sig}"), 1_700_000_000).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_tampering_and_replays() {
+        let sig = RequestSigning::new(SECRET).sign(1_700_000_000, BODY);
+        let at = |ts: &str, sig: &str, body: &[u8], now| {
+            verify_signature_at(SECRET, ts, sig, body, TOLERANCE, now)
+        };
+        let err = at("1700000000", &sig, b"[]", 1_700_000_000).unwrap_err();
+        assert!(err.contains("mismatch"), "{err}");
+        assert!(at("1700000001", &sig, BODY, 1_700_000_000).is_err());
+        let err = at("1700000000", &sig, BODY, 1_700_000_301).unwrap_err();
+        assert!(err.contains("tolerance"), "{err}");
+        // Signed in the future beyond the tolerance (clock skew or forgery)
+        assert!(at("1700000000", &sig, BODY, 1_699_999_000).is_err());
+        assert!(at("soon", &sig, BODY, 1_700_000_000).is_err());
+        assert!(at("1700000000", "v1=zz", BODY, 1_700_000_000).is_err());
+        assert!(at("1700000000", &sig[3..], BODY, 1_700_000_000).is_err());
+    }
+
+    #[test]
+    fn round_trip_with_current_time() {
+        let (ts, sig) = RequestSigning::new(SECRET).headers(BODY);
+        assert!(verify_signature(SECRET, &ts, &sig, BODY, TOLERANCE).is_ok());
+    }
+}
@@ -1,91 +1,351 @@
-use std::net::IpAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
-/// Check if a hostname/IP is a private or reserved address.
-///
-/// Covers: RFC 1918, CGNAT (100.64/10), loopback, link-local,
-/// IPv6 ULA/link-local, IPv4-mapped IPv6.
-pub fn is_private_ip(host: &str) -> bool {
-    let addr: IpAddr = match host.parse() {
-        Ok(a) => a,
-        Err(_) => return false,
-    };
-    is_private_addr(addr)
+/// An IP network in CIDR notation (`10.0.0.0/8`, `2001:db8::/32`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u8,
 }
 
-fn is_private_addr(addr: IpAddr) -> bool {
-    match addr {
-        IpAddr::V4(v4) => {
-            let octets = v4.octets();
-            // 0.0.0.0/8
-            if octets[0] == 0 {
-                return true;
-            }
-            // 10.0.0.0/8
-            if octets[0] == 10 {
-                return true;
-            }
-            // 172.16.0.0/12
-            if octets[0] == 172 && (16..=31).contains(&octets[1]) {
-                return true;
-            }
-            // 192.168.0.0/16
-            if octets[0] == 192 && octets[1] == 168 {
-                return true;
-            }
-            // 127.0.0.0/8 (loopback)
-            if octets[0] == 127 {
-                return true;
-            }
-            // 169.254.0.0/16 (link-local)
-            if octets[0] == 169 && octets[1] == 254 {
-                return true;
-            }
-            // 100.64.0.0/10 (CGNAT, RFC 6598)
-            if octets[0] == 100 && (64..=127).contains(&octets[1]) {
-                return true;
-            }
-            false
+impl Cidr {
+    /// Parse `addr/prefix`. A bare address is treated as a single host.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("[peekapi] Invalid CIDR: {s}");
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
         }
-        IpAddr::V6(v6) => {
-            // ::1 (loopback)
-            if v6.is_loopback() {
-                return true;
-            }
-            let segments = v6.segments();
-            // fc00::/7 (ULA)
-            if segments[0] & 0xfe00 == 0xfc00 {
-                return true;
+        Ok(Self { network, prefix })
+    }
+
+    /// Whether `addr` falls inside this network. IPv4-mapped IPv6 addresses
+    /// match IPv4 networks.
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6
+                .to_ipv4_mapped()
+                .filter(|_| self.network.is_ipv4())
+                .map_or(addr, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(a) & mask
             }
-            // fe80::/10 (link-local)
-            if segments[0] & 0xffc0 == 0xfe80 {
-                return true;
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(a) & mask
             }
-            // IPv4-mapped IPv6 (::ffff:x.x.x.x)
-            if let Some(v4) = v6.to_ipv4_mapped() {
-                return is_private_addr(IpAddr::V4(v4));
+            _ => false,
+        }
+    }
+}
+
+/// Parse an IP address that may carry a port or IPv6 brackets
+/// (`1.2.3.4:80`, `[::1]:443`, `[::1]`).
+pub(crate) fn parse_ip_lenient(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = s.strip_prefix('[') {
+        return rest
+            .split(']')
+            .next()?
+            .parse::<Ipv6Addr>()
+            .ok()
+            .map(IpAddr::V6);
+    }
+    let (host, _port) = s.rsplit_once(':')?;
+    host.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+const fn v4(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Cidr {
+    Cidr {
+        network: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        prefix,
+    }
+}
+
+const fn v6(segments: [u16; 8], prefix: u8) -> Cidr {
+    let [a, b, c, d, e, f, g, h] = segments;
+    Cidr {
+        network: IpAddr::V6(Ipv6Addr::new(a, b, c, d, e, f, g, h)),
+        prefix,
+    }
+}
+
+/// Special-purpose ranges that are not globally reachable, after the IANA
+/// IPv4/IPv6 Special-Purpose Address Registries. More specific entries come
+/// first so the reported reason is the most precise one.
+///
+/// IPv6 ranges that embed an IPv4 address (IPv4-mapped, IPv4-compatible,
+/// NAT64, 6to4, Teredo) are handled by [`embedded_ipv4`] instead.
+const SPECIAL_PURPOSE: &[(Cidr, &str)] = &[
+    // IPv4
+    (v4(0, 0, 0, 0, 8), "\"this network\" (RFC 791)"),
+    (v4(10, 0, 0, 0, 8), "private-use (RFC 1918)"),
+    (
+        v4(100, 64, 0, 0, 10),
+        "shared address space / CGNAT (RFC 6598)",
+    ),
+    (v4(127, 0, 0, 0, 8), "loopback (RFC 1122)"),
+    (v4(169, 254, 0, 0, 16), "link-local (RFC 3927)"),
+    (v4(172, 16, 0, 0, 12), "private-use (RFC 1918)"),
+    (v4(192, 0, 0, 0, 24), "IETF protocol assignments (RFC 6890)"),
+    (v4(192, 0, 2, 0, 24), "documentation, TEST-NET-1 (RFC 5737)"),
+    (
+        v4(192, 88, 99, 0, 24),
+        "deprecated 6to4 relay anycast (RFC 7526)",
+    ),
+    (v4(192, 168, 0, 0, 16), "private-use (RFC 1918)"),
+    (v4(198, 18, 0, 0, 15), "benchmarking (RFC 2544)"),
+    (
+        v4(198, 51, 100, 0, 24),
+        "documentation, TEST-NET-2 (RFC 5737)",
+    ),
+    (
+        v4(203, 0, 113, 0, 24),
+        "documentation, TEST-NET-3 (RFC 5737)",
+    ),
+    (v4(224, 0, 0, 0, 4), "multicast (RFC 5771)"),
+    (v4(255, 255, 255, 255, 32), "limited broadcast (RFC 919)"),
+    (v4(240, 0, 0, 0, 4), "reserved (RFC 1112)"),
+    // IPv6
+    (
+        v6([0, 0, 0, 0, 0, 0, 0, 0], 128),
+        "unspecified address (RFC 4291)",
+    ),
+    (v6([0, 0, 0, 0, 0, 0, 0, 1], 128), "loopback (RFC 4291)"),
+    (
+        v6([0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48),
+        "local-use NAT64 (RFC 8215)",
+    ),
+    (
+        v6([0x100, 0, 0, 0, 0, 0, 0, 0], 64),
+        "discard-only (RFC 6666)",
+    ),
+    (
+        v6([0x2001, 2, 0, 0, 0, 0, 0, 0], 48),
+        "benchmarking (RFC 5180)",
+    ),
+    (
+        v6([0x2001, 0x10, 0, 0, 0, 0, 0, 0], 28),
+        "deprecated ORCHID (RFC 4843)",
+    ),
+    (
+        v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32),
+        "documentation (RFC 3849)",
+    ),
+    (
+        v6([0x3fff, 0, 0, 0, 0, 0, 0, 0], 20),
+        "documentation (RFC 9637)",
+    ),
+    (
+        v6([0x5f00, 0, 0, 0, 0, 0, 0, 0], 16),
+        "segment routing SIDs (RFC 9602)",
+    ),
+    (
+        v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+        "unique local (RFC 4193)",
+    ),
+    (
+        v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+        "link-local (RFC 4291)",
+    ),
+    (
+        v6([0xfec0, 0, 0, 0, 0, 0, 0, 0], 10),
+        "deprecated site-local (RFC 3879)",
+    ),
+    (v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8), "multicast (RFC 4291)"),
+];
+
+/// The IPv4 address embedded in an IPv6 address by a transition mechanism,
+/// with the mechanism's name.
+fn embedded_ipv4(v6: Ipv6Addr) -> Option<(Ipv4Addr, &'static str)> {
+    let bits = u128::from(v6);
+    let low32 = Ipv4Addr::from(bits as u32);
+    let s = v6.segments();
+    match s {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => Some((low32, "IPv4-mapped")),
+        [0, 0, 0, 0, 0, 0, _, _] => Some((low32, "IPv4-compatible")),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some((low32, "NAT64")),
+        [0x2002, hi, lo, ..] => Some((
+            Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)),
+            "6to4",
+        )),
+        [0x2001, 0, ..] => Some((Ipv4Addr::from(!(bits as u32)), "Teredo client")),
+        _ => None,
+    }
+}
+
+/// Why an address is private or reserved, or `None` if it is globally
+/// reachable.
+fn private_addr_reason(addr: IpAddr) -> Option<String> {
+    if let Some((_, reason)) = SPECIAL_PURPOSE.iter().find(|(net, _)| {
+        // Match IPv4-mapped addresses through `embedded_ipv4` so the reason
+        // names the mechanism
+        net.network.is_ipv4() == addr.is_ipv4() && net.contains(addr)
+    }) {
+        return Some(reason.to_string());
+    }
+    let IpAddr::V6(v6) = addr else {
+        return None;
+    };
+    let (v4, mechanism) = embedded_ipv4(v6)?;
+    if let Some(reason) = private_addr_reason(IpAddr::V4(v4)) {
+        return Some(format!("{mechanism} address embedding {v4}, {reason}"));
+    }
+    if mechanism == "Teredo client" {
+        // The Teredo server address sits in bits 32..64
+        let server = Ipv4Addr::from((u128::from(v6) >> 64) as u32);
+        if let Some(reason) = private_addr_reason(IpAddr::V4(server)) {
+            return Some(format!("Teredo address with server {server}, {reason}"));
+        }
+    }
+    None
+}
+
+/// Check if a hostname/IP is a private or reserved address.
+///
+/// Covers the IANA special-purpose registries (private-use, CGNAT, loopback,
+/// link-local, documentation, benchmarking, multicast, broadcast, reserved,
+/// IPv6 ULA, discard-only, ...) and IPv6 addresses that embed a private IPv4
+/// (IPv4-mapped, IPv4-compatible, NAT64, 6to4, Teredo). Hostnames are not
+/// resolved and return `false`.
+pub fn is_private_ip(host: &str) -> bool {
+    private_ip_reason(host).is_some()
+}
+
+/// Why `host` is a private or reserved address, e.g.
+/// `"documentation, TEST-NET-3 (RFC 5737)"`. `None` for public addresses and
+/// hostnames.
+pub fn private_ip_reason(host: &str) -> Option<String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    private_addr_reason(host.parse().ok()?)
+}
+
+/// Which endpoint hosts are acceptable beyond the built-in rules.
+///
+/// Entries are host names (`ingest.corp.example`), wildcard suffixes
+/// (`*.corp.example`), IP addresses or CIDR ranges (`10.20.0.0/16`).
+#[derive(Debug, Clone, Default)]
+pub struct EndpointPolicy {
+    /// Hosts permitted even if they are (or resolve to) private addresses.
+    pub allowed_hosts: Vec<String>,
+    /// Permit only hosts on `allowed_hosts`.
+    pub strict: bool,
+    /// Hosts permitted over plain HTTP, in addition to localhost.
+    pub insecure_http_hosts: Vec<String>,
+}
+
+impl EndpointPolicy {
+    /// Add a host, wildcard suffix, IP or CIDR to the allow-list.
+    pub fn allow(mut self, entry: impl Into<String>) -> Self {
+        self.allowed_hosts.push(entry.into());
+        self
+    }
+
+    /// Permit only allow-listed hosts.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Permit plain HTTP for a host (same entry syntax as `allow`).
+    pub fn allow_http(mut self, entry: impl Into<String>) -> Self {
+        self.insecure_http_hosts.push(entry.into());
+        self
+    }
+
+    /// Reject entries that look like CIDR ranges but do not parse.
+    fn check(&self) -> Result<(), String> {
+        for entry in self.allowed_hosts.iter().chain(&self.insecure_http_hosts) {
+            if entry.contains('/') {
+                Cidr::parse(entry)?;
             }
-            false
         }
+        Ok(())
+    }
+
+    fn allows_host(&self, host: &str) -> bool {
+        self.allowed_hosts.iter().any(|e| entry_matches(e, host))
+    }
+
+    fn allows_addr(&self, addr: IpAddr) -> bool {
+        self.allowed_hosts
+            .iter()
+            .any(|e| Cidr::parse(e).is_ok_and(|c| c.contains(addr)))
+    }
+
+    fn allows_http(&self, host: &str) -> bool {
+        self.insecure_http_hosts
+            .iter()
+            .any(|e| entry_matches(e, host))
+    }
+}
+
+fn entry_matches(entry: &str, host: &str) -> bool {
+    let entry = entry.trim().trim_end_matches('.');
+    let host = host.trim_end_matches('.');
+    if let Ok(cidr) = Cidr::parse(entry) {
+        return host.parse().is_ok_and(|ip| cidr.contains(ip));
+    }
+    match entry.strip_prefix("*.") {
+        Some(suffix) => host
+            .len()
+            .checked_sub(suffix.len() + 1)
+            .is_some_and(|i| host[i..].eq_ignore_ascii_case(&format!(".{suffix}"))),
+        None => host.eq_ignore_ascii_case(entry),
     }
 }
 
 /// Validate and normalize the ingestion endpoint URL.
 ///
-/// Returns the validated endpoint string, or an error for:
+/// Returns the validated endpoint string (unchanged), or an error for:
+///   - Schemes other than `http`/`https`
 ///   - Non-HTTPS URLs (except localhost)
 ///   - Private/reserved IP addresses (SSRF protection)
 ///   - Embedded credentials in URL
+///   - Non-canonical numeric IPv4 hosts (`0x7f000001`, `2130706433`, `0177.1`)
 ///   - Malformed URLs
 pub fn validate_endpoint(endpoint: &str) -> Result<String, String> {
+    validate_endpoint_with(endpoint, &EndpointPolicy::default())
+}
+
+/// Same as [`validate_endpoint`], with allow-listed hosts exempt from the
+/// private-address check, plain HTTP permitted for `insecure_http_hosts`,
+/// and only allow-listed hosts accepted in strict mode.
+pub fn validate_endpoint_with(endpoint: &str, policy: &EndpointPolicy) -> Result<String, String> {
     if endpoint.is_empty() {
         return Err("[peekapi] 'endpoint' is required".to_string());
     }
+    policy.check()?;
 
     let url = url_parse(endpoint)?;
 
-    let is_localhost = url.host == "localhost" || url.host == "127.0.0.1" || url.host == "::1";
+    let is_localhost = is_localhost(&url.host);
+    let allowed = policy.allows_host(&url.host);
+
+    if policy.strict && !allowed {
+        return Err(format!(
+            "[peekapi] Endpoint host is not on the allow-list: {}",
+            url.host
+        ));
+    }
 
-    if url.scheme != "https" && !is_localhost {
+    if url.scheme != "https" && !is_localhost && !policy.allows_http(&url.host) {
         return Err(format!(
             "[peekapi] Endpoint must use HTTPS. Plain HTTP is only allowed for localhost: {endpoint}"
         ));
@@ -95,61 +355,161 @@ pub fn validate_endpoint(endpoint: &str) -> Result<String, String> {
         return Err("[peekapi] Endpoint URL must not contain credentials".to_string());
     }
 
-    if !is_localhost && is_private_ip(&url.host) {
+    if !is_localhost && !allowed {
+        if let Some(reason) = private_ip_reason(&url.host) {
+            return Err(format!(
+                "[peekapi] Endpoint must not point to a private or internal IP address: {} ({reason})",
+                url.host
+            ));
+        }
+    }
+
+    Ok(endpoint.to_string())
+}
+
+/// Hosts exempt from the HTTPS and private-address rules, for local development.
+pub(crate) fn is_localhost(host: &str) -> bool {
+    matches!(host, "localhost" | "127.0.0.1" | "::1")
+}
+
+/// Resolves hostnames to socket addresses.
+///
+/// Used when `dns_validation` is enabled; implement it to plug in a custom
+/// DNS client or to stub resolution in tests. Closures
+/// `Fn(&str, u16) -> io::Result<Vec<SocketAddr>>` implement it too.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync,
+{
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self(host, port)
+    }
+}
+
+/// The operating system resolver (`getaddrinfo`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        (host, port).to_socket_addrs().map(Iterator::collect)
+    }
+}
+
+/// Resolve `host` and reject it if any resolved address is private or
+/// reserved. Returns the validated addresses, which callers should connect to
+/// directly so a second lookup cannot return something else.
+///
+/// Localhost and hosts allow-listed by `policy` are exempt, and addresses
+/// inside allow-listed CIDR ranges are accepted, matching
+/// [`validate_endpoint_with`].
+pub fn resolve_and_validate(
+    host: &str,
+    port: u16,
+    resolver: &dyn Resolver,
+    policy: &EndpointPolicy,
+) -> Result<Vec<SocketAddr>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = resolver
+        .resolve(host, port)
+        .map_err(|e| format!("[peekapi] Failed to resolve endpoint host {host}: {e}"))?;
+    if addrs.is_empty() {
         return Err(format!(
-            "[peekapi] Endpoint must not point to a private or internal IP address: {}",
-            url.host
+            "[peekapi] Endpoint host {host} did not resolve to any address"
         ));
     }
+    if !is_localhost(host) && !policy.allows_host(host) {
+        for addr in &addrs {
+            if policy.allows_addr(addr.ip()) {
+                continue;
+            }
+            if let Some(reason) = private_addr_reason(addr.ip()) {
+                return Err(format!(
+                    "[peekapi] Endpoint host {host} resolves to a private or internal IP address: {} ({reason})",
+                    addr.ip()
+                ));
+            }
+        }
+    }
+    Ok(addrs)
+}
 
-    Ok(endpoint.to_string())
+/// Host and port of a validated endpoint URL (default port by scheme).
+pub(crate) fn endpoint_host_port(endpoint: &str) -> Result<(String, u16), String> {
+    let url = url_parse(endpoint)?;
+    let port = url
+        .port
+        .unwrap_or(if url.scheme == "https" { 443 } else { 80 });
+    Ok((url.host, port))
 }
 
 struct ParsedUrl {
     scheme: String,
     host: String,
+    port: Option<u16>,
     has_credentials: bool,
 }
 
+/// Parse an endpoint with the WHATWG URL parser, the same one the HTTP client
+/// uses, so the host validated here is the host that gets connected to.
+/// Hosts come back canonical: lowercase, percent-decoded, IDNs in punycode.
 fn url_parse(endpoint: &str) -> Result<ParsedUrl, String> {
-    // Minimal URL parsing — avoids pulling in the `url` crate
-    let (scheme, rest) = endpoint
-        .split_once("://")
-        .ok_or_else(|| format!("[peekapi] Invalid endpoint URL: {endpoint}"))?;
-
-    let authority = rest.split('/').next().unwrap_or(rest);
-    let has_credentials = authority.contains('@');
-
-    // Strip credentials for host extraction
-    let host_port = if has_credentials {
-        authority.rsplit_once('@').map_or(authority, |(_, hp)| hp)
-    } else {
-        authority
-    };
-
-    // Strip port
-    let host = if host_port.starts_with('[') {
-        // IPv6: [::1]:8080
-        host_port
-            .split(']')
-            .next()
-            .unwrap_or(host_port)
-            .trim_start_matches('[')
-    } else {
-        host_port.split(':').next().unwrap_or(host_port)
+    let invalid = |detail: &dyn std::fmt::Display| {
+        format!("[peekapi] Invalid endpoint URL ({detail}): {endpoint}")
     };
+    // WHATWG repairs inputs like `https:///host` or `https:host`; require
+    // the plain `scheme://host` form so the host is what it appears to be
+    if raw_host(endpoint).is_none_or(str::is_empty) {
+        return Err(invalid(&"expected scheme://host"));
+    }
+    let url = url::Url::parse(endpoint).map_err(|e| invalid(&e))?;
 
-    if host.is_empty() {
-        return Err(format!("[peekapi] Invalid endpoint URL: {endpoint}"));
+    let scheme = url.scheme().to_string();
+    if scheme != "https" && scheme != "http" {
+        return Err(invalid(&format_args!("unsupported scheme '{scheme}'")));
     }
 
+    let host = match url.host() {
+        Some(url::Host::Domain(domain)) => domain.to_string(),
+        Some(url::Host::Ipv4(v4)) => {
+            // WHATWG accepts decimal, octal, hex and shortened IPv4 forms
+            // (`2130706433`, `0x7f.1`); only dotted-decimal is allowed so the
+            // address is unambiguous to every reader of the config
+            let canonical = v4.to_string();
+            if !raw_host(endpoint).is_some_and(|raw| raw.eq_ignore_ascii_case(&canonical)) {
+                return Err(invalid(&format_args!(
+                    "ambiguous IPv4 address, use {canonical}"
+                )));
+            }
+            canonical
+        }
+        Some(url::Host::Ipv6(v6)) => v6.to_string(),
+        None => return Err(invalid(&"missing host")),
+    };
+
     Ok(ParsedUrl {
-        scheme: scheme.to_lowercase(),
-        host: host.to_lowercase(),
-        has_credentials,
+        scheme,
+        host,
+        port: url.port(),
+        has_credentials: !url.username().is_empty() || url.password().is_some(),
     })
 }
 
+/// The host as written in `endpoint`, before any normalization.
+fn raw_host(endpoint: &str) -> Option<&str> {
+    let (_, rest) = endpoint.split_once("://")?;
+    let authority = rest.split(['/', '\\', '?', '#']).next().unwrap_or(rest);
+    let host_port = authority.rsplit_once('@').map_or(authority, |(_, hp)| hp);
+    if host_port.starts_with('[') {
+        return host_port.split_once(']').map(|(h, _)| &h[1..]);
+    }
+    Some(host_port.split(':').next().unwrap_or(host_port))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -173,7 +533,7 @@ mod tests {
     fn public_ipv4() {
         assert!(!is_private_ip("8.8.8.8"));
         assert!(!is_private_ip("1.1.1.1"));
-        assert!(!is_private_ip("203.0.113.1"));
+        assert!(!is_private_ip("93.184.216.34"));
     }
 
     #[test]
@@ -184,6 +544,71 @@ mod tests {
         assert!(is_private_ip("fe80::1"));
     }
 
+    #[test]
+    fn special_purpose_ipv4_ranges() {
+        for (ip, reason) in [
+            ("192.0.0.8", "IETF protocol assignments"),
+            ("192.0.2.1", "TEST-NET-1"),
+            ("198.51.100.1", "TEST-NET-2"),
+            ("203.0.113.1", "TEST-NET-3"),
+            ("198.18.0.1", "benchmarking"),
+            ("198.19.255.255", "benchmarking"),
+            ("224.0.0.1", "multicast"),
+            ("239.255.255.250", "multicast"),
+            ("240.0.0.1", "reserved"),
+            ("255.255.255.255", "limited broadcast"),
+            ("192.88.99.1", "6to4 relay"),
+        ] {
+            let got = private_ip_reason(ip).unwrap_or_default();
+            assert!(got.contains(reason), "{ip}: {got}");
+        }
+        assert!(!is_private_ip("198.20.0.1"));
+        assert!(!is_private_ip("223.255.255.255"));
+    }
+
+    #[test]
+    fn special_purpose_ipv6_ranges() {
+        for (ip, reason) in [
+            ("::", "unspecified"),
+            ("ff02::1", "multicast"),
+            ("fec0::1", "site-local"),
+            ("2001:db8::1", "documentation"),
+            ("3fff::1", "documentation"),
+            ("100::1", "discard-only"),
+            ("64:ff9b:1::1", "local-use NAT64"),
+        ] {
+            let got = private_ip_reason(ip).unwrap_or_default();
+            assert!(got.contains(reason), "{ip}: {got}");
+        }
+        assert!(!is_private_ip("2606:4700::1111"));
+    }
+
+    #[test]
+    fn ipv6_embedding_private_ipv4() {
+        for (ip, mechanism) in [
+            ("::ffff:10.0.0.1", "IPv4-mapped"),
+            ("::10.0.0.1", "IPv4-compatible"),
+            ("64:ff9b::127.0.0.1", "NAT64"),
+            ("2002:c0a8:0101::1", "6to4"),
+            // Teredo client 192.168.1.1 (obfuscated: 3f57:fefe)
+            ("2001:0:4136:e378:8000:63bf:3f57:fefe", "Teredo"),
+        ] {
+            let got = private_ip_reason(ip).unwrap_or_default();
+            assert!(got.contains(mechanism), "{ip}: {got}");
+        }
+        // Embedding public addresses is fine
+        assert!(!is_private_ip("::ffff:8.8.8.8"));
+        assert!(!is_private_ip("64:ff9b::8.8.8.8"));
+        assert!(!is_private_ip("2002:0808:0808::1"));
+        assert!(!is_private_ip("2001:0:4136:e378:8000:63bf:f7f7:f7f7"));
+    }
+
+    #[test]
+    fn validate_reports_reason() {
+        let err = validate_endpoint("https://[ff02::1]/ingest").unwrap_err();
+        assert!(err.contains("multicast"), "{err}");
+    }
+
     #[test]
     fn non_ip_hostname_is_not_private() {
         assert!(!is_private_ip("example.com"));
@@ -226,6 +651,130 @@ mod tests {
         assert!(validate_endpoint("").is_err());
     }
 
+    #[test]
+    fn cidr_contains() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        let host = Cidr::parse("192.0.2.7").unwrap();
+        assert!(host.contains("192.0.2.7".parse().unwrap()));
+        assert!(!host.contains("192.0.2.8".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_rejects_invalid() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("not-an-ip/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn parse_ip_lenient_strips_ports() {
+        assert_eq!(parse_ip_lenient("1.2.3.4:80"), "1.2.3.4".parse().ok());
+        assert_eq!(parse_ip_lenient("[::1]:443"), "::1".parse().ok());
+        assert_eq!(
+            parse_ip_lenient(" 2001:db8::1 "),
+            "2001:db8::1".parse().ok()
+        );
+        assert_eq!(parse_ip_lenient("unknown"), None);
+    }
+
+    fn stub(ip: &'static str) -> impl Resolver {
+        move |_: &str, port: u16| Ok(vec![SocketAddr::new(ip.parse().unwrap(), port)])
+    }
+
+    #[test]
+    fn resolve_and_validate_rejects_private_answers() {
+        let none = EndpointPolicy::default();
+        let err = resolve_and_validate("internal.example.com", 443, &stub("10.0.0.5"), &none)
+            .unwrap_err();
+        assert!(err.contains("10.0.0.5"));
+
+        let addrs =
+            resolve_and_validate("api.example.com", 443, &stub("93.184.216.34"), &none).unwrap();
+        assert_eq!(addrs, vec!["93.184.216.34:443".parse().unwrap()]);
+
+        // Localhost is exempt, as in validate_endpoint
+        assert!(resolve_and_validate("localhost", 8080, &stub("127.0.0.1"), &none).is_ok());
+    }
+
+    #[test]
+    fn resolve_and_validate_rejects_failures() {
+        let none = EndpointPolicy::default();
+        let failing = |_: &str, _: u16| Err(io::Error::other("no such host"));
+        assert!(resolve_and_validate("nope.example.com", 443, &failing, &none).is_err());
+        let empty = |_: &str, _: u16| Ok(Vec::new());
+        assert!(resolve_and_validate("empty.example.com", 443, &empty, &none).is_err());
+    }
+
+    #[test]
+    fn policy_allow_list_permits_private_hosts() {
+        let policy = EndpointPolicy::default()
+            .allow("10.20.0.0/16")
+            .allow("*.corp.example");
+        assert!(validate_endpoint_with("https://10.20.1.5/ingest", &policy).is_ok());
+        assert!(validate_endpoint_with("https://10.21.1.5/ingest", &policy).is_err());
+        // Public hosts still pass without strict mode
+        assert!(validate_endpoint_with("https://api.example.com/", &policy).is_ok());
+
+        // Allow-listed names and CIDRs accept private DNS answers
+        assert!(
+            resolve_and_validate("ingest.corp.example", 443, &stub("10.9.9.9"), &policy).is_ok()
+        );
+        assert!(resolve_and_validate("other.example", 443, &stub("10.20.3.4"), &policy).is_ok());
+        assert!(resolve_and_validate("other.example", 443, &stub("10.9.9.9"), &policy).is_err());
+    }
+
+    #[test]
+    fn policy_strict_mode_and_insecure_http() {
+        let policy = EndpointPolicy::default()
+            .allow("ingest.corp.example")
+            .allow_http("ingest.corp.example")
+            .strict(true);
+        assert!(validate_endpoint_with("http://ingest.corp.example:8080/", &policy).is_ok());
+        assert!(validate_endpoint_with("http://INGEST.corp.example./", &policy).is_ok());
+        let err = validate_endpoint_with("https://api.example.com/", &policy).unwrap_err();
+        assert!(err.contains("allow-list"));
+        assert!(validate_endpoint_with("http://evil.corp.example/", &policy).is_err());
+
+        // Wildcards match subdomains only
+        assert!(entry_matches("*.corp.example", "a.corp.example"));
+        assert!(!entry_matches("*.corp.example", "corp.example"));
+        assert!(!entry_matches("*.corp.example", "evilcorp.example"));
+    }
+
+    #[test]
+    fn policy_rejects_invalid_cidr() {
+        let policy = EndpointPolicy::default().allow("10.0.0.0/40");
+        assert!(validate_endpoint_with("https://api.example.com/", &policy).is_err());
+    }
+
+    #[test]
+    fn endpoint_host_port_defaults_by_scheme() {
+        assert_eq!(
+            endpoint_host_port("https://api.example.com/ingest").unwrap(),
+            ("api.example.com".to_string(), 443)
+        );
+        assert_eq!(
+            endpoint_host_port("http://localhost:8080/ingest").unwrap(),
+            ("localhost".to_string(), 8080)
+        );
+        assert_eq!(
+            endpoint_host_port("https://[2001:db8::1]:9443/").unwrap(),
+            ("2001:db8::1".to_string(), 9443)
+        );
+    }
+
     #[test]
     fn validate_rejects_malformed() {
         assert!(validate_endpoint("not-a-url").is_err());
@@ -1,9 +1,25 @@
+use crate::capture::{BodyCapture, HeaderCapture};
+use crate::client_ip::ClientIpOptions;
+use crate::consumer::{ApiKeyPolicy, ConsumerChain, ConsumerHashing, JwtIdentifier};
+use crate::format::PayloadFormat;
+use crate::query::QueryStringPolicy;
+use crate::request::RequestView;
+use crate::signing::RequestSigning;
+use crate::ssrf::{EndpointPolicy, Resolver};
+use crate::tls::TlsOptions;
+use crate::transport::{ProxyOptions, TransportOptions};
+
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 /// Error callback type for background flush errors.
 pub type ErrorCallback = Box<dyn Fn(&dyn std::error::Error) + Send + Sync>;
 
+/// Supplies a fresh ingestion API key, e.g. from a secrets manager.
+pub type ApiKeyProviderFn = Box<dyn Fn() -> Option<String> + Send + Sync>;
+
 /// Callback for custom consumer identification.
 ///
 /// Receives a header-getter closure (same interface as `default_identify_consumer`)
@@ -11,6 +27,10 @@ pub type ErrorCallback = Box<dyn Fn(&dyn std::error::Error) + Send + Sync>;
 pub type IdentifyConsumerFn =
     Box<dyn Fn(&dyn Fn(&str) -> Option<String>) -> Option<String> + Send + Sync>;
 
+/// Callback for custom consumer identification with access to the whole
+/// request (method, path, query, headers, peer address, identities).
+pub type IdentifyRequestFn = Box<dyn Fn(&dyn RequestView) -> Option<String> + Send + Sync>;
+
 /// A single captured API request event.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RequestEvent {
@@ -26,16 +46,74 @@ pub struct RequestEvent {
     pub consumer_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
     #[serde(default)]
     pub timestamp: String,
+    /// Unique event ID (UUIDv7), assigned by `track` when empty. Stays the
+    /// same across retries and disk recovery so the ingestion side can
+    /// deduplicate resent events.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub event_id: String,
+}
+
+impl RequestEvent {
+    /// Insert `value` under `key` in the event metadata, creating the metadata
+    /// object if needed. Existing non-object metadata is kept under `value`.
+    pub fn merge_metadata(&mut self, key: &str, value: serde_json::Value) {
+        if !matches!(self.metadata, Some(serde_json::Value::Object(_))) {
+            let mut map = serde_json::Map::new();
+            if let Some(existing) = self.metadata.take() {
+                map.insert("value".to_string(), existing);
+            }
+            self.metadata = Some(serde_json::Value::Object(map));
+        }
+        if let Some(serde_json::Value::Object(ref mut map)) = self.metadata {
+            map.insert(key.to_string(), value);
+        }
+    }
 }
 
 /// Configuration for the API dashboard client.
 pub struct Options {
     /// API key for authenticating with the ingestion endpoint (required).
     pub api_key: String,
+    /// Called when the endpoint returns 401, to fetch a rotated key; the
+    /// batch is retried once with it. Also supplies the initial key when
+    /// `api_key` is empty. See also `PeekApiClient::set_api_key`.
+    pub api_key_provider: Option<ApiKeyProviderFn>,
     /// URL of the ingestion endpoint. Default: PeekAPI cloud.
     pub endpoint: String,
+    /// Allow-list, strict mode and plain-HTTP exceptions for the endpoint host.
+    pub endpoint_policy: EndpointPolicy,
+    /// Redirects (307/308 only) to follow when sending events. Each hop is
+    /// validated like the endpoint, and `x-api-key` is dropped once a
+    /// redirect leaves the endpoint's origin. Default: 0 (not followed).
+    pub max_redirects: u32,
+    /// Extra headers sent with every ingestion request (e.g. tenant routing
+    /// or tracing). Reserved names (`x-api-key`, `content-type`,
+    /// `user-agent`, `x-peekapi-*`, ...) are rejected. Like `x-api-key`,
+    /// they are dropped once a redirect leaves the endpoint's origin.
+    pub headers: BTreeMap<String, String>,
+    /// Sign each request with HMAC-SHA256 over timestamp and body
+    /// (`x-peekapi-timestamp`, `x-peekapi-signature`). Default: `None`.
+    pub signing: Option<RequestSigning>,
+    /// Resolve the endpoint host and reject private or reserved addresses,
+    /// at construction and again on every connection (DNS rebinding
+    /// protection). Through a proxy the host is re-checked before each
+    /// request instead, as the proxy does the final lookup.
+    /// Default: false (only literal IPs are checked).
+    pub dns_validation: bool,
+    /// Resolver used for the endpoint host. Default: the system resolver.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// HTTP proxy for ingestion requests (explicit or from the environment).
+    /// Default: `None` (direct connections; proxy variables are ignored).
+    pub proxy: Option<ProxyOptions>,
+    /// Extra root certificates, client certificate (mTLS) and SPKI pins for
+    /// the ingestion endpoint. Default: `None` (bundled Web PKI roots).
+    pub tls: Option<TlsOptions>,
+    /// Connection pooling, keep-alive, timeouts, user agent and DNS caching.
+    pub transport: TransportOptions,
     /// Time between automatic flushes. Default: 15s.
     pub flush_interval: Duration,
     /// Number of events that triggers an automatic flush. Default: 250.
@@ -48,17 +126,56 @@ pub struct Options {
     pub max_event_bytes: usize,
     /// Include sorted query parameters in the tracked path.
     /// NOTE: increases DB usage — each unique path+query creates a separate endpoint row.
+    /// See `query_string` for allow-listing and value stripping.
     pub collect_query_string: bool,
+    /// Fine-grained query string normalization (allow-list, value stripping,
+    /// numeric bucketing). Setting this enables query collection; when `None`
+    /// and `collect_query_string` is true, all parameters are kept verbatim.
+    pub query_string: Option<QueryStringPolicy>,
+    /// Request/response headers to copy into event metadata under `headers`.
+    pub capture_headers: Option<HeaderCapture>,
+    /// Opt-in request/response body capture for failed calls (Actix Web and Axum only).
+    pub capture_body: Option<BodyCapture>,
+    /// Capture the client IP (with trusted-proxy handling and anonymization).
+    pub client_ip: Option<ClientIpOptions>,
     /// Enable debug logging to stderr.
     pub debug: bool,
     /// File path for persisting undelivered events.
     /// Default: `<temp_dir>/peekapi-events-<hash>.jsonl`
     pub storage_path: Option<String>,
+    /// Encoding of ingestion request bodies, sent as `Content-Type`. If the
+    /// endpoint answers 415, the client falls back to JSON. Default: `Json`.
+    pub payload_format: PayloadFormat,
+    /// Encoding of the storage file. Files in another format are still
+    /// recovered. Default: `Json` (one array per line).
+    pub storage_format: PayloadFormat,
+    /// File for events the ingestion API will never accept (non-retryable
+    /// errors, permanent per-event rejections). They are kept out of
+    /// `storage_path` so they are not resent.
+    /// Default: `storage_path` with `.jsonl` replaced by `.dead-letter.jsonl`
+    pub dead_letter_path: Option<String>,
+    /// Maximum size of the dead-letter file in bytes; further events are
+    /// discarded. Default: 1MB.
+    pub max_dead_letter_bytes: u64,
     /// Optional error callback invoked from the background thread.
     pub on_error: Option<ErrorCallback>,
+    /// How `x-api-key` values are stored as consumer IDs. Default: hashed.
+    pub api_key_policy: ApiKeyPolicy,
+    /// Keyed (HMAC) hashing for credentials used as consumer IDs.
+    /// Default: unsalted SHA-256 (`hash_consumer_id`).
+    pub consumer_hashing: Option<ConsumerHashing>,
+    /// Identify bearer-token consumers by a JWT claim (e.g. `sub`) instead of
+    /// hashing the whole `Authorization` header.
+    pub jwt_identifier: Option<JwtIdentifier>,
+    /// Ordered identification strategies replacing the default
+    /// `x-api-key` → `Authorization` order. Ignored if a callback is set.
+    pub consumer_chain: Option<ConsumerChain>,
     /// Optional callback for custom consumer identification.
     /// Receives a header-getter closure and returns an optional consumer ID.
     pub identify_consumer: Option<IdentifyConsumerFn>,
+    /// Optional callback for custom consumer identification from the full
+    /// request. Takes precedence over `identify_consumer`.
+    pub identify_request: Option<IdentifyRequestFn>,
 }
 
 impl Options {
@@ -71,17 +188,40 @@ impl Options {
     pub fn new(api_key: impl Into<String>, endpoint: impl Into<String>) -> Self {
         Self {
             api_key: api_key.into(),
+            api_key_provider: None,
             endpoint: endpoint.into(),
+            endpoint_policy: EndpointPolicy::default(),
+            max_redirects: 0,
+            headers: BTreeMap::new(),
+            signing: None,
+            dns_validation: false,
+            resolver: None,
+            proxy: None,
+            tls: None,
+            transport: TransportOptions::default(),
             flush_interval: Duration::from_secs(15),
             batch_size: 250,
             max_buffer_size: 10_000,
             max_storage_bytes: 5_242_880,
             max_event_bytes: 65_536,
             collect_query_string: false,
+            query_string: None,
+            capture_headers: None,
+            capture_body: None,
+            client_ip: None,
             debug: false,
             storage_path: None,
+            payload_format: PayloadFormat::Json,
+            storage_format: PayloadFormat::Json,
+            dead_letter_path: None,
+            max_dead_letter_bytes: 1_048_576,
             on_error: None,
+            api_key_policy: ApiKeyPolicy::default(),
+            consumer_hashing: None,
+            jwt_identifier: None,
+            consumer_chain: None,
             identify_consumer: None,
+            identify_request: None,
         }
     }
 }
@@ -0,0 +1,200 @@
+#![cfg(feature = "actix")]
+
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{test, web, App, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::stream;
+use peekapi::middleware::actix::PeekApi;
+use peekapi::{BodyCapture, ConsumerIdentity, Options, PeekApiClient, RequestEvent};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Auth state an application extractor stores on the request.
+struct Claims {
+    sub: String,
+}
+
+/// Ingestion stand-in that answers one request with 200 and sends its body.
+fn ingest_server() -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        tx.send(String::from_utf8(body).unwrap()).unwrap();
+    });
+    (format!("http://127.0.0.1:{port}/ingest"), rx)
+}
+
+async fn declined(body: String) -> HttpResponse {
+    assert_eq!(body, r#"{"card_number":"4111","amount":5}"#);
+    let chunks: Vec<Result<Bytes, std::io::Error>> =
+        [r#"{"error":"declined","#, r#""token":"t0k"}"#]
+            .iter()
+            .map(|p| Ok(Bytes::from_static(p.as_bytes())))
+            .collect();
+    HttpResponse::PaymentRequired()
+        .content_type("application/json")
+        .streaming(stream::iter(chunks))
+}
+
+#[actix_web::test]
+async fn captured_bodies_stream_through_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    opts.capture_body = Some(BodyCapture::default());
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .wrap(PeekApi::new(Arc::clone(&client)))
+            .route("/api/pay", web::post().to(declined)),
+    )
+    .await;
+    let request = test::TestRequest::post()
+        .uri("/api/pay")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(r#"{"card_number":"4111","amount":5}"#)
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    let body = test::read_body(response).await;
+    assert_eq!(&body[..], br#"{"error":"declined","token":"t0k"}"#);
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    let captured = &batch[0].metadata.as_ref().unwrap()["body"];
+    assert_eq!(
+        captured["request"]["data"],
+        r#"{"amount":5,"card_number":"[REDACTED]"}"#
+    );
+    assert_eq!(
+        captured["response"]["data"],
+        r#"{"error":"declined","token":"[REDACTED]"}"#
+    );
+    client.shutdown();
+}
+
+#[actix_web::test]
+async fn identify_request_reads_auth_extensions() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    opts.identify_request = Some(Box::new(|req| {
+        req.extensions().get::<Claims>().map(|c| c.sub.clone())
+    }));
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = test::init_service(App::new().wrap(PeekApi::new(Arc::clone(&client))).route(
+        "/api/users",
+        web::get().to(|req: HttpRequest| async move {
+            req.extensions_mut().insert(Claims {
+                sub: "user_42".to_string(),
+            });
+            HttpResponse::Ok().finish()
+        }),
+    ))
+    .await;
+    test::call_service(
+        &app,
+        test::TestRequest::get().uri("/api/users").to_request(),
+    )
+    .await;
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(batch[0].consumer_id.as_deref(), Some("user_42"));
+    client.shutdown();
+}
+
+#[actix_web::test]
+async fn inner_identities_are_seen_on_the_request_or_the_response() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .wrap(PeekApi::new(Arc::clone(&client)))
+            .route(
+                "/from-request",
+                web::get().to(|req: HttpRequest| async move {
+                    req.extensions_mut().insert(ConsumerIdentity::new("acct_7"));
+                    HttpResponse::Ok().finish()
+                }),
+            )
+            .route(
+                "/from-response",
+                web::get().to(|| async {
+                    let mut resp = HttpResponse::Ok().finish();
+                    resp.extensions_mut()
+                        .insert(ConsumerIdentity::new("acct_8"));
+                    resp
+                }),
+            ),
+    )
+    .await;
+    for uri in ["/from-request", "/from-response"] {
+        let request = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("x-api-key", "ak_live_1"))
+            .to_request();
+        test::call_service(&app, request).await;
+    }
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(batch[0].consumer_id.as_deref(), Some("acct_7"));
+    assert_eq!(batch[1].consumer_id.as_deref(), Some("acct_8"));
+    client.shutdown();
+}
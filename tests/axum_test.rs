@@ -0,0 +1,246 @@
+#![cfg(feature = "axum-middleware")]
+
+use axum::body::{Body, Bytes};
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Extension;
+use axum::Router;
+use futures_util::stream;
+use http_body_util::BodyExt;
+use peekapi::middleware::axum::PeekApiLayer;
+use peekapi::{
+    BodyCapture, ClientIpOptions, ConsumerIdentity, IpAnonymization, Options, PeekApiClient,
+    RequestEvent,
+};
+
+/// Auth state an application layer stores on the request.
+#[derive(Clone)]
+struct Claims {
+    sub: String,
+}
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+/// Ingestion stand-in that answers one request with 200 and sends its body.
+fn ingest_server() -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        tx.send(String::from_utf8(body).unwrap()).unwrap();
+    });
+    (format!("http://127.0.0.1:{port}/ingest"), rx)
+}
+
+fn chunked(parts: &[&'static str]) -> Body {
+    let chunks: Vec<Result<Bytes, std::io::Error>> = parts
+        .iter()
+        .map(|p| Ok(Bytes::from_static(p.as_bytes())))
+        .collect();
+    Body::from_stream(stream::iter(chunks))
+}
+
+async fn declined(body: String) -> Response {
+    assert_eq!(body, r#"{"card_number":"4111","amount":5}"#);
+    (
+        StatusCode::PAYMENT_REQUIRED,
+        [("content-type", "application/json")],
+        chunked(&[r#"{"error":"declined","#, r#""token":"t0k"}"#]),
+    )
+        .into_response()
+}
+
+#[tokio::test]
+async fn captured_bodies_stream_through_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    opts.capture_body = Some(BodyCapture::default());
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = Router::new()
+        .route("/api/pay", post(declined))
+        .layer(PeekApiLayer::new(client.clone()));
+    let request = Request::post("/api/pay")
+        .header("content-type", "application/json")
+        .body(chunked(&[r#"{"card_number":"4111","#, r#""amount":5}"#]))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], br#"{"error":"declined","token":"t0k"}"#);
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    let captured = &batch[0].metadata.as_ref().unwrap()["body"];
+    assert_eq!(
+        captured["request"]["data"],
+        r#"{"amount":5,"card_number":"[REDACTED]"}"#
+    );
+    assert_eq!(
+        captured["response"]["data"],
+        r#"{"error":"declined","token":"[REDACTED]"}"#
+    );
+    client.shutdown();
+}
+
+#[tokio::test]
+async fn client_ip_uses_the_proxy_appended_forwarding_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    opts.client_ip = Some(ClientIpOptions {
+        trusted_proxies: vec!["10.0.0.0/8".to_string()],
+        anonymization: IpAnonymization::None,
+        ..Default::default()
+    });
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = Router::new()
+        .route("/api/users", get(|| async { "ok" }))
+        .layer(PeekApiLayer::new(client.clone()));
+    // The client sent Forwarded and its own X-Forwarded-For line; the
+    // trusted proxy added a second X-Forwarded-For line
+    let mut request = Request::get("/api/users")
+        .header("forwarded", "for=1.2.3.4")
+        .header("x-forwarded-for", "6.6.6.6")
+        .header("x-forwarded-for", "198.51.100.7")
+        .body(Body::empty())
+        .unwrap();
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 40000))));
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(batch[0].client_ip.as_deref(), Some("198.51.100.7"));
+    client.shutdown();
+}
+
+#[tokio::test]
+async fn identify_request_reads_auth_extensions() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    opts.identify_request = Some(Box::new(|req| {
+        req.extensions().get::<Claims>().map(|c| c.sub.clone())
+    }));
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = Router::new()
+        .route("/api/users", get(|| async { "ok" }))
+        .layer(PeekApiLayer::new(client.clone()));
+    // As inserted by an auth layer outside PeekApiLayer
+    let mut request = Request::get("/api/users").body(Body::empty()).unwrap();
+    request.extensions_mut().insert(Claims {
+        sub: "user_42".to_string(),
+    });
+    app.oneshot(request).await.unwrap();
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(batch[0].consumer_id.as_deref(), Some("user_42"));
+    client.shutdown();
+}
+
+#[tokio::test]
+async fn inner_identities_are_taken_from_the_response() {
+    let dir = tempfile::tempdir().unwrap();
+    let (endpoint, rx) = ingest_server();
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let app = Router::new()
+        .route(
+            "/from-handler",
+            get(|| async { (Extension(ConsumerIdentity::new("acct_7")), "ok") }),
+        )
+        .route(
+            "/from-inner-layer",
+            get(|| async { "ok" }).layer(axum::middleware::map_request(
+                |mut req: Request<Body>| async move {
+                    req.extensions_mut().insert(ConsumerIdentity::new("acct_8"));
+                    req
+                },
+            )),
+        )
+        .layer(PeekApiLayer::new(client.clone()));
+    for uri in ["/from-handler", "/from-inner-layer"] {
+        let request = Request::get(uri)
+            .header("x-api-key", "ak_live_1")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap();
+    }
+
+    client.flush();
+    let batch: Vec<RequestEvent> =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(batch[0].consumer_id.as_deref(), Some("acct_7"));
+    // Inserted into the request after PeekApiLayer identified it: the
+    // x-api-key identity stands
+    assert_ne!(batch[1].consumer_id.as_deref(), Some("acct_8"));
+    assert!(batch[1].consumer_id.is_some());
+    client.shutdown();
+}
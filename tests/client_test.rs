@@ -1,6 +1,14 @@
-use peekapi::{Options, PeekApiClient, RequestEvent};
-use std::sync::Arc;
-use std::time::Duration;
+use peekapi::{
+    verify_signature, ClientIpOptions, ConsumerChain, ConsumerHashing, DeadLetterReason,
+    EndpointPolicy, IdEncoding, IpAnonymization, Options, PayloadFormat, PeekApiClient,
+    ProxyOptions, QueryStringPolicy, QueryValueMode, RequestEvent, RequestInfo, RequestSigning,
+    TransportOptions,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 fn test_event() -> RequestEvent {
     RequestEvent {
@@ -12,7 +20,9 @@ fn test_event() -> RequestEvent {
         response_size: 128,
         consumer_id: Some("ak_test_123".to_string()),
         metadata: None,
+        client_ip: None,
         timestamp: String::new(),
+        event_id: String::new(),
     }
 }
 
@@ -348,3 +358,1563 @@ fn collect_query_string_getter() {
     assert!(client.collect_query_string());
     client.shutdown();
 }
+
+#[test]
+fn query_string_policy_enables_collection() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.query_string = Some(QueryStringPolicy::allow(["page"]).values(QueryValueMode::Strip));
+    let client = PeekApiClient::new(opts).unwrap();
+
+    assert!(client.collect_query_string());
+    assert!(client.query_string_policy().is_some());
+    assert_eq!(
+        client.tracked_path("/users", Some("page=2&token=x")),
+        "/users?page="
+    );
+    assert_eq!(client.tracked_path("/users", Some("token=x")), "/users");
+    client.shutdown();
+}
+
+#[test]
+fn merge_metadata_adds_structured_keys() {
+    let mut event = test_event();
+    event.merge_metadata(
+        "headers",
+        serde_json::json!({"request": {"user-agent": "sdk/1"}}),
+    );
+    assert_eq!(
+        event.metadata.as_ref().unwrap()["headers"]["request"]["user-agent"],
+        "sdk/1"
+    );
+
+    // Existing non-object metadata is preserved under `value`
+    event.metadata = Some(serde_json::json!("note"));
+    event.merge_metadata("headers", serde_json::json!({}));
+    let meta = event.metadata.unwrap();
+    assert_eq!(meta["value"], "note");
+    assert!(meta["headers"].is_object());
+}
+
+#[test]
+fn oversized_event_drops_captured_bodies_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path.clone());
+    opts.flush_interval = Duration::from_secs(60);
+    opts.max_event_bytes = 1024;
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let mut event = test_event();
+    event.merge_metadata(
+        "headers",
+        serde_json::json!({"request": {"user-agent": "sdk/1"}}),
+    );
+    event.merge_metadata(
+        "body",
+        serde_json::json!({"request": {"data": "x".repeat(4096)}}),
+    );
+    client.track(event);
+    assert_eq!(client.buffer_len(), 1);
+    client.shutdown();
+
+    let persisted = std::fs::read_to_string(&path).unwrap();
+    let record: serde_json::Value = serde_json::from_str(persisted.trim()).unwrap();
+    let batch: Vec<RequestEvent> = serde_json::from_value(record["events"].clone()).unwrap();
+    let meta = batch[0].metadata.as_ref().unwrap();
+    assert!(meta.get("body").is_none());
+    assert_eq!(meta["headers"]["request"]["user-agent"], "sdk/1");
+}
+
+#[test]
+fn client_ip_resolves_through_trusted_proxy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.client_ip = Some(ClientIpOptions {
+        trusted_proxies: vec!["10.0.0.0/8".to_string()],
+        anonymization: IpAnonymization::Truncate,
+        ..Default::default()
+    });
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let ip = client.client_ip("10.1.2.3".parse().ok(), |name| {
+        (name == "x-forwarded-for").then(|| "198.51.100.77".to_string())
+    });
+    assert_eq!(ip.as_deref(), Some("198.51.100.0"));
+    client.shutdown();
+}
+
+#[test]
+fn client_ip_disabled_by_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let client = make_client(&path);
+    assert!(client
+        .client_ip("198.51.100.77".parse().ok(), |_| None)
+        .is_none());
+    client.shutdown();
+}
+
+#[test]
+fn new_rejects_invalid_trusted_proxy() {
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.client_ip = Some(ClientIpOptions {
+        trusted_proxies: vec!["10.0.0.0/40".to_string()],
+        ..Default::default()
+    });
+    assert!(PeekApiClient::new(opts).is_err());
+}
+
+#[test]
+fn keyed_consumer_hashing_with_legacy_correlation() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let mut hashing = ConsumerHashing::new("s3cret");
+    hashing.emit_legacy_hash = true;
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.consumer_hashing = Some(hashing.clone());
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let request = RequestInfo::new("GET", "/").with_header("Authorization", "Bearer tok");
+    let consumer_id = client.identify(&request);
+    assert_eq!(consumer_id, Some(hashing.hash("Bearer tok")));
+    assert_eq!(
+        client.legacy_consumer_id(&request, consumer_id.as_deref()),
+        Some(peekapi::hash_consumer_id("Bearer tok"))
+    );
+    // Nothing to correlate when the IDs match
+    let legacy = peekapi::hash_consumer_id("Bearer tok");
+    assert_eq!(client.legacy_consumer_id(&request, Some(&legacy)), None);
+    client.shutdown();
+}
+
+#[test]
+fn consumer_chain_overrides_default_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.consumer_chain = Some(
+        ConsumerChain::new()
+            .query_param("client_id", IdEncoding::Raw)
+            .api_key(),
+    );
+    let client = PeekApiClient::new(opts).unwrap();
+
+    let mut request = RequestInfo::new("GET", "/orders").with_header("x-api-key", "ak_live_1");
+    request.query = Some("client_id=mobile".to_string());
+    assert_eq!(client.identify(&request).as_deref(), Some("mobile"));
+    request.query = None;
+    assert_eq!(
+        client.identify(&request),
+        Some(peekapi::hash_consumer_id("ak_live_1"))
+    );
+    client.shutdown();
+}
+
+#[test]
+fn identify_request_callback_and_consumer_identity() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Application-provided identity wins over headers by default
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path.clone());
+    opts.flush_interval = Duration::from_secs(60);
+    let client = PeekApiClient::new(opts).unwrap();
+    let mut request = RequestInfo::new("GET", "/tenants/acme/orders").with_header("x-api-key", "k");
+    assert_eq!(
+        client.identify(&request),
+        Some(peekapi::hash_consumer_id("k"))
+    );
+    request.consumer_identity = Some("customer-7".to_string());
+    assert_eq!(client.identify(&request).as_deref(), Some("customer-7"));
+    client.shutdown();
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.identify_request = Some(Box::new(|req| {
+        req.path()
+            .split('/')
+            .nth(2)
+            .map(|s| format!("{}:{s}", req.method()))
+    }));
+    let client = PeekApiClient::new(opts).unwrap();
+    assert_eq!(client.identify(&request).as_deref(), Some("GET:acme"));
+    client.shutdown();
+}
+
+#[test]
+fn dns_validation_rejects_private_resolution_at_construction() {
+    let mut opts = Options::new("ak_test", "https://internal.example.com/ingest");
+    opts.dns_validation = true;
+    opts.resolver = Some(Arc::new(|_: &str, port: u16| {
+        Ok(vec![SocketAddr::from(([10, 0, 0, 5], port))])
+    }));
+    let err = PeekApiClient::new(opts).err().unwrap();
+    assert!(err.contains("10.0.0.5"), "{err}");
+}
+
+#[test]
+fn dns_validation_revalidates_on_send() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Public at construction, private afterwards (DNS rebinding)
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&lookups);
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&errors);
+
+    let mut opts = Options::new("ak_test", "https://rebind.example.com/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.dns_validation = true;
+    opts.resolver = Some(Arc::new(move |_: &str, port: u16| {
+        let ip = match counter.fetch_add(1, Ordering::SeqCst) {
+            0 => [93, 184, 216, 34],
+            _ => [10, 0, 0, 5],
+        };
+        Ok(vec![SocketAddr::from((ip, port))])
+    }));
+    opts.on_error = Some(Box::new(move |e| sink.lock().unwrap().push(e.to_string())));
+    let client = PeekApiClient::new(opts).unwrap();
+
+    client.track(test_event());
+    client.flush();
+
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("private"), "{}", errors[0]);
+    // Transport errors are retryable: the event stays buffered
+    assert_eq!(client.buffer_len(), 1);
+    client.shutdown();
+}
+
+#[test]
+fn endpoint_policy_permits_on_prem_ingestion() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let opts = Options::new("ak_test", "http://10.20.0.5:8080/ingest");
+    assert!(PeekApiClient::new(opts).is_err());
+
+    let mut opts = Options::new("ak_test", "http://10.20.0.5:8080/ingest");
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.endpoint_policy = EndpointPolicy::default()
+        .allow("10.20.0.0/16")
+        .allow_http("10.20.0.5")
+        .strict(true);
+    let client = PeekApiClient::new(opts).unwrap();
+    client.shutdown();
+}
+
+/// Minimal HTTP server: answers each connection with the next canned
+/// response and forwards the raw request (head and body) on the channel.
+fn mock_server(responses: Vec<String>) -> (String, mpsc::Receiver<String>) {
+    let (endpoint, raw) = raw_mock_server(responses);
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for (head, body) in raw {
+            let request = format!("{head}\r\n{}", String::from_utf8_lossy(&body));
+            if tx.send(request).is_err() {
+                return;
+            }
+        }
+    });
+    (endpoint, rx)
+}
+
+/// Like `mock_server`, but records the header block and the raw body
+/// separately, for binary payloads.
+fn raw_mock_server(responses: Vec<String>) -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for response in responses {
+            let Ok((mut stream, _)) = listener.accept() else {
+                return;
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            tx.send((head, body)).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (format!("http://127.0.0.1:{port}"), rx)
+}
+
+fn http_response(status: &str, extra_headers: &str) -> String {
+    format!("HTTP/1.1 {status}\r\n{extra_headers}Content-Length: 0\r\nConnection: close\r\n\r\n")
+}
+
+fn redirect_client(
+    endpoint: &str,
+    max_redirects: u32,
+) -> (
+    Arc<PeekApiClient>,
+    Arc<Mutex<Vec<String>>>,
+    tempfile::TempDir,
+) {
+    recording_client(endpoint, |opts| opts.max_redirects = max_redirects)
+}
+
+/// Client that records flush errors, with `configure` applied to its options.
+fn recording_client(
+    endpoint: &str,
+    configure: impl FnOnce(&mut Options),
+) -> (
+    Arc<PeekApiClient>,
+    Arc<Mutex<Vec<String>>>,
+    tempfile::TempDir,
+) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&errors);
+
+    let mut opts = Options::new("ak_test", endpoint);
+    opts.storage_path = Some(path);
+    opts.flush_interval = Duration::from_secs(60);
+    opts.on_error = Some(Box::new(move |e| sink.lock().unwrap().push(e.to_string())));
+    configure(&mut opts);
+    (PeekApiClient::new(opts).unwrap(), errors, dir)
+}
+
+#[test]
+fn redirects_are_not_followed_by_default() {
+    let (target, target_rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (origin, origin_rx) = mock_server(vec![http_response(
+        "307 Temporary Redirect",
+        &format!("Location: {target}/ingest\r\n"),
+    )]);
+    let (client, errors, _dir) = redirect_client(&format!("{origin}/ingest"), 0);
+
+    client.track(test_event());
+    client.flush();
+
+    assert!(origin_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    assert!(target_rx.try_recv().is_err());
+    assert!(errors.lock().unwrap()[0].contains("307"));
+    // Kept for a retry, not dead-lettered
+    assert_eq!(client.buffer_len(), 1);
+    assert!(client.dead_letters().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn redirects_strip_api_key_across_origins() {
+    let (target, target_rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (origin, origin_rx) = mock_server(vec![
+        http_response("308 Permanent Redirect", "Location: /v2/ingest\r\n"),
+        http_response(
+            "307 Temporary Redirect",
+            &format!("Location: {target}/ingest\r\n"),
+        ),
+    ]);
+    let (client, errors, _dir) = redirect_client(&format!("{origin}/ingest"), 2);
+
+    client.track(test_event());
+    client.flush();
+
+    let first = origin_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = origin_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let third = target_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(first.starts_with("POST /ingest "));
+    assert!(second.starts_with("POST /v2/ingest "));
+    assert!(second.to_lowercase().contains("x-api-key: ak_test"));
+    assert!(!third.to_lowercase().contains("x-api-key"));
+    assert!(third.contains("/api/users"));
+    assert!(errors.lock().unwrap().is_empty());
+    assert_eq!(client.buffer_len(), 0);
+    client.shutdown();
+}
+
+#[test]
+fn redirects_to_private_addresses_are_refused() {
+    let (origin, _origin_rx) = mock_server(vec![http_response(
+        "307 Temporary Redirect",
+        "Location: https://169.254.169.254/latest/meta-data\r\n",
+    )]);
+    let (client, errors, _dir) = redirect_client(&format!("{origin}/ingest"), 3);
+
+    client.track(test_event());
+    client.flush();
+
+    let errors = errors.lock().unwrap();
+    assert!(errors[0].contains("Redirect refused"), "{}", errors[0]);
+    assert!(errors[0].contains("169.254.169.254"), "{}", errors[0]);
+    client.shutdown();
+}
+
+#[test]
+fn plain_http_endpoint_is_forwarded_through_proxy() {
+    let (proxy, proxy_rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client("http://localhost:9/v1/events", |opts| {
+        opts.proxy = Some(ProxyOptions::url(&proxy).credentials("svc", "secret"));
+    });
+
+    client.track(test_event());
+    client.flush();
+
+    let request = proxy_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(
+        request.starts_with("POST http://localhost:9/v1/events HTTP/1.1"),
+        "{request}"
+    );
+    assert!(request.contains("Proxy-Authorization: Basic c3ZjOnNlY3JldA=="));
+    assert!(errors.lock().unwrap().is_empty());
+    assert_eq!(client.buffer_len(), 0);
+    client.shutdown();
+}
+
+#[test]
+fn https_endpoint_is_tunnelled_with_connect() {
+    let (proxy, proxy_rx) =
+        mock_server(vec![http_response("407 Proxy Authentication Required", "")]);
+    let proxy = proxy.replace("http://", "http://svc:secret@");
+    let (client, errors, _dir) = recording_client("https://ingest.example.com/v1/events", |opts| {
+        opts.proxy = Some(ProxyOptions::url(proxy));
+    });
+
+    client.track(test_event());
+    client.flush();
+
+    let connect = proxy_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(
+        connect.starts_with("CONNECT ingest.example.com:443 HTTP/1.1"),
+        "{connect}"
+    );
+    assert!(connect.contains("Proxy-Authorization: basic c3ZjOnNlY3JldA=="));
+    // The API key never leaves the tunnel
+    assert!(!connect.to_lowercase().contains("x-api-key"));
+    assert_eq!(errors.lock().unwrap().len(), 1);
+    assert_eq!(client.buffer_len(), 1);
+    client.shutdown();
+}
+
+#[test]
+fn proxy_does_not_exempt_the_endpoint_from_validation() {
+    let mut opts = Options::new("ak_test", "https://169.254.169.254/v1/events");
+    opts.proxy = Some(ProxyOptions::url("http://10.0.0.1:3128"));
+    assert!(PeekApiClient::new(opts).is_err());
+
+    let mut opts = Options::new("ak_test", "https://ingest.example.com/v1/events");
+    opts.proxy = Some(ProxyOptions::url("https://proxy.example.com"));
+    let err = PeekApiClient::new(opts).err().unwrap();
+    assert!(err.contains("Unsupported proxy scheme"), "{err}");
+}
+
+#[test]
+fn dns_validation_rechecks_endpoint_host_through_proxy() {
+    let (proxy, proxy_rx) = mock_server(vec![http_response("200 OK", "")]);
+    let endpoint_lookups = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&endpoint_lookups);
+    let (client, errors, _dir) = recording_client("https://rebind.example.com/v1/events", |opts| {
+        opts.proxy = Some(ProxyOptions::url(&proxy));
+        opts.dns_validation = true;
+        opts.resolver = Some(Arc::new(move |host: &str, port: u16| {
+            // The (internal) proxy host itself is not checked
+            if host == "127.0.0.1" {
+                return Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))]);
+            }
+            let ip = match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => [93, 184, 216, 34],
+                _ => [10, 0, 0, 5],
+            };
+            Ok(vec![SocketAddr::from((ip, port))])
+        }));
+    });
+
+    client.track(test_event());
+    client.flush();
+
+    assert_eq!(endpoint_lookups.load(Ordering::SeqCst), 2);
+    assert!(proxy_rx.try_recv().is_err());
+    let errors = errors.lock().unwrap();
+    assert!(errors[0].contains("private"), "{}", errors[0]);
+    assert_eq!(client.buffer_len(), 1);
+    client.shutdown();
+}
+
+/// Mock ingestion server that keeps connections open. Each request is
+/// reported with the index of the connection it arrived on.
+fn keep_alive_server() -> (String, mpsc::Receiver<(usize, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for (conn, stream) in listener.incoming().enumerate() {
+            let Ok(mut stream) = stream else { return };
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut request = String::new();
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap_or(0);
+                            }
+                        }
+                        request.push_str(&line);
+                    }
+                    let mut body = vec![0; content_length];
+                    if reader.read_exact(&mut body).is_err() {
+                        return;
+                    }
+                    let _ = tx.send((conn, request));
+                    let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                    if stream.write_all(response.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (format!("http://127.0.0.1:{port}/v1/events"), rx)
+}
+
+/// Flush one event per round and return the connection index of each request.
+fn flush_rounds(
+    client: &PeekApiClient,
+    rx: &mpsc::Receiver<(usize, String)>,
+    rounds: usize,
+    pause: Duration,
+) -> Vec<usize> {
+    (0..rounds)
+        .map(|_| {
+            std::thread::sleep(pause);
+            client.track(test_event());
+            client.flush();
+            rx.recv_timeout(Duration::from_secs(5)).unwrap().0
+        })
+        .collect()
+}
+
+#[test]
+fn connections_are_reused_across_flushes() {
+    let (endpoint, rx) = keep_alive_server();
+    let (client, errors, _dir) = recording_client(&endpoint, |_| {});
+
+    assert_eq!(flush_rounds(&client, &rx, 3, Duration::ZERO), vec![0, 0, 0]);
+
+    // The final flush on shutdown uses the same connection
+    client.track(test_event());
+    client.shutdown();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().0, 0);
+    assert!(errors.lock().unwrap().is_empty());
+}
+
+#[test]
+fn keep_alive_can_be_disabled() {
+    let (endpoint, rx) = keep_alive_server();
+    let (client, _errors, _dir) = recording_client(&endpoint, |opts| {
+        opts.transport = TransportOptions {
+            keep_alive: false,
+            ..Default::default()
+        };
+    });
+
+    assert_eq!(flush_rounds(&client, &rx, 2, Duration::ZERO), vec![0, 1]);
+    client.shutdown();
+}
+
+#[test]
+fn idle_connections_are_discarded_after_idle_timeout() {
+    let (endpoint, rx) = keep_alive_server();
+    let (client, _errors, _dir) = recording_client(&endpoint, |opts| {
+        opts.transport.idle_timeout = Some(Duration::from_millis(50));
+    });
+
+    assert_eq!(
+        flush_rounds(&client, &rx, 2, Duration::from_millis(100)),
+        vec![0, 1]
+    );
+    client.shutdown();
+}
+
+#[test]
+fn timeout_bounds_the_whole_request() {
+    // Each header byte arrives well within read_timeout, the whole response
+    // does not
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    std::thread::spawn(move || {
+        let Ok((mut stream, _)) = listener.accept() else {
+            return;
+        };
+        let mut buf = [0; 4096];
+        let _ = stream.read(&mut buf);
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nX-Padding: ");
+        for _ in 0..50 {
+            std::thread::sleep(Duration::from_millis(100));
+            if stream.write_all(b"a").is_err() {
+                return;
+            }
+        }
+    });
+    let (client, errors, _dir) = recording_client(&endpoint, |opts| {
+        opts.transport.timeout = Duration::from_millis(300);
+    });
+
+    client.track(test_event());
+    let started = Instant::now();
+    client.flush();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(client.buffer_len(), 1);
+    assert_eq!(errors.lock().unwrap().len(), 1);
+    client.shutdown();
+}
+
+#[test]
+fn user_agent_is_configurable() {
+    let (endpoint, rx) = keep_alive_server();
+    let (client, _errors, _dir) = recording_client(&endpoint, |_| {});
+    client.track(test_event());
+    client.flush();
+    let (_, request) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.contains(&format!(
+        "User-Agent: peekapi-rust/{}",
+        env!("CARGO_PKG_VERSION")
+    )));
+    client.shutdown();
+
+    let (endpoint, rx) = keep_alive_server();
+    let (client, _errors, _dir) = recording_client(&endpoint, |opts| {
+        opts.transport.user_agent = "billing-api/2.1".to_string();
+    });
+    client.track(test_event());
+    client.flush();
+    let (_, request) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.contains("User-Agent: billing-api/2.1"), "{request}");
+    client.shutdown();
+
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.transport.user_agent = "x\r\nInjected: 1".to_string();
+    assert!(PeekApiClient::new(opts).is_err());
+    let mut opts = Options::new("ak_test", &endpoint);
+    opts.transport.user_agent = "billing-api/2.1 (caf\u{e9})".to_string();
+    assert!(PeekApiClient::new(opts).is_err());
+}
+
+#[test]
+fn dns_cache_reuses_answers_within_ttl() {
+    let (endpoint, rx) = keep_alive_server();
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&lookups);
+    let endpoint = endpoint.replace("127.0.0.1", "localhost");
+    let (client, errors, _dir) = recording_client(&endpoint, |opts| {
+        opts.transport.keep_alive = false;
+        opts.transport.dns_cache_ttl = Some(Duration::from_secs(60));
+        opts.resolver = Some(Arc::new(move |_: &str, port: u16| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+        }));
+    });
+
+    assert_eq!(flush_rounds(&client, &rx, 3, Duration::ZERO), vec![0, 1, 2]);
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn custom_headers_are_sent_with_every_request() {
+    let (endpoint, rx) = keep_alive_server();
+    let (client, errors, _dir) = recording_client(&endpoint, |opts| {
+        opts.headers
+            .insert("X-Tenant".to_string(), "acme-eu".to_string());
+        opts.headers
+            .insert("traceparent".to_string(), "00-abc-def-01".to_string());
+    });
+    client.track(test_event());
+    client.flush();
+
+    let (_, request) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.contains("X-Tenant: acme-eu\r\n"), "{request}");
+    assert!(request.contains("traceparent: 00-abc-def-01\r\n"));
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn custom_headers_are_validated() {
+    for (name, value) in [
+        ("x-api-key", "spoofed"),
+        ("Content-Type", "text/plain"),
+        ("User-Agent", "other"),
+        ("X-PeekAPI-SDK", "go/1.0"),
+        ("x-peekapi-signature", "forged"),
+        ("X-Tenant", "acme\r\nX-Injected: 1"),
+        ("X-Tenant", "acme\0"),
+        ("X-Tenant", "acme\x7f"),
+        ("X-Tenant", "acm\u{e9}"),
+        ("X-Tenant", "acme\x1b[0m"),
+        ("X Tenant", "acme"),
+        ("", "acme"),
+    ] {
+        let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+        opts.headers.insert(name.to_string(), value.to_string());
+        assert!(PeekApiClient::new(opts).is_err(), "{name:?}: {value:?}");
+    }
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.headers.insert("X-Tenant".to_string(), "a".to_string());
+    opts.headers.insert("x-tenant".to_string(), "b".to_string());
+    let err = PeekApiClient::new(opts).err().unwrap();
+    assert!(err.contains("Duplicate header"), "{err}");
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.headers
+        .insert("X-Tenant".to_string(), "acme\tcorp ~1".to_string());
+    assert!(PeekApiClient::new(opts).is_ok());
+}
+
+#[test]
+fn custom_headers_are_dropped_across_origins() {
+    let (target, target_rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (origin, origin_rx) = mock_server(vec![http_response(
+        "307 Temporary Redirect",
+        &format!("Location: {target}/ingest\r\n"),
+    )]);
+    let (client, _errors, _dir) = recording_client(&format!("{origin}/ingest"), |opts| {
+        opts.max_redirects = 1;
+        opts.headers
+            .insert("X-Tenant".to_string(), "acme-eu".to_string());
+    });
+    client.track(test_event());
+    client.flush();
+
+    let first = origin_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = target_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(first.contains("X-Tenant: acme-eu"));
+    assert!(!second.contains("X-Tenant"));
+    client.shutdown();
+}
+
+/// Header value from a raw request captured by a mock server.
+fn raw_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        k.eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+#[test]
+fn signed_requests_verify_with_the_shared_secret() {
+    let (endpoint, rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.signing = Some(RequestSigning::new("whsec_test"));
+    });
+    client.track(test_event());
+    client.flush();
+
+    let request = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let (head, body) = request.split_once("\r\n\r\n").unwrap();
+    let timestamp = raw_header(head, peekapi::TIMESTAMP_HEADER).unwrap();
+    let signature = raw_header(head, peekapi::SIGNATURE_HEADER).unwrap();
+    let tolerance = Duration::from_secs(300);
+    peekapi::verify_signature(
+        b"whsec_test",
+        timestamp,
+        signature,
+        body.as_bytes(),
+        tolerance,
+    )
+    .unwrap();
+    assert!(
+        peekapi::verify_signature(b"wrong", timestamp, signature, body.as_bytes(), tolerance)
+            .is_err()
+    );
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.signing = Some(RequestSigning::new(""));
+    assert!(PeekApiClient::new(opts).is_err());
+}
+
+#[test]
+fn signature_headers_are_dropped_across_origins() {
+    let (target, target_rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (origin, origin_rx) = mock_server(vec![http_response(
+        "307 Temporary Redirect",
+        &format!("Location: {target}/ingest\r\n"),
+    )]);
+    let (client, _errors, _dir) = recording_client(&format!("{origin}/ingest"), |opts| {
+        opts.max_redirects = 1;
+        opts.signing = Some(RequestSigning::new("whsec_test"));
+    });
+    client.track(test_event());
+    client.flush();
+
+    let first = origin_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = target_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(raw_header(&first, "x-peekapi-signature").is_some());
+    assert!(raw_header(&second, "x-peekapi-signature").is_none());
+    assert!(raw_header(&second, "x-peekapi-timestamp").is_none());
+    client.shutdown();
+}
+
+#[test]
+fn set_api_key_applies_to_subsequent_sends() {
+    let (endpoint, rx) = keep_alive_server();
+    let (client, errors, _dir) = recording_client(&endpoint, |_| {});
+
+    client.track(test_event());
+    client.flush();
+    let (_, first) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(raw_header(&first, "x-api-key"), Some("ak_test"));
+
+    client.set_api_key("ak_rotated").unwrap();
+    assert!(client.set_api_key("bad\nkey").is_err());
+    assert!(client.set_api_key("").is_err());
+    client.track(test_event());
+    client.flush();
+    let (_, second) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(raw_header(&second, "x-api-key"), Some("ak_rotated"));
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn unauthorized_batch_is_retried_once_with_provider_key() {
+    let (endpoint, rx) = mock_server(vec![
+        http_response("401 Unauthorized", ""),
+        http_response("200 OK", ""),
+    ]);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.api_key_provider = Some(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Some("ak_rotated".to_string())
+        }));
+    });
+    client.track(test_event());
+    client.flush();
+
+    let first = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(raw_header(&first, "x-api-key"), Some("ak_test"));
+    assert_eq!(raw_header(&second, "x-api-key"), Some("ak_rotated"));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(errors.lock().unwrap().is_empty());
+    assert_eq!(client.buffer_len(), 0);
+    client.shutdown();
+}
+
+#[test]
+fn unauthorized_batch_is_not_retried_without_a_new_key() {
+    // Provider returns the rejected key again: no retry
+    let (endpoint, rx) = mock_server(vec![http_response("401 Unauthorized", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.api_key_provider = Some(Box::new(|| Some("ak_test".to_string())));
+    });
+    client.track(test_event());
+    client.flush();
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    assert!(errors.lock().unwrap()[0].contains("401"));
+    client.shutdown();
+
+    // A second 401 after the refresh is reported, not retried again
+    let (endpoint, rx) = mock_server(vec![
+        http_response("401 Unauthorized", ""),
+        http_response("401 Unauthorized", ""),
+        http_response("200 OK", ""),
+    ]);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.api_key_provider = Some(Box::new(move || {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            Some(format!("ak_rotated_{n}"))
+        }));
+    });
+    client.track(test_event());
+    client.flush();
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(errors.lock().unwrap()[0].contains("401"));
+    client.shutdown();
+}
+
+#[test]
+fn api_key_provider_supplies_the_initial_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut opts = Options::new("", "http://localhost:9999/ingest");
+    opts.api_key_provider = Some(Box::new(|| Some("ak_from_vault".to_string())));
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    PeekApiClient::new(opts).unwrap().shutdown();
+
+    let mut opts = Options::new("", "http://localhost:9999/ingest");
+    opts.api_key_provider = Some(Box::new(|| None));
+    assert!(PeekApiClient::new(opts).is_err());
+}
+
+/// Event IDs and `Idempotency-Key` of a recorded ingestion request.
+fn delivered_ids(request: &str) -> (Vec<String>, String) {
+    let (head, body) = request.split_once("\r\n\r\n").unwrap();
+    let batch: Vec<RequestEvent> = serde_json::from_str(body).unwrap();
+    let key = raw_header(head, "idempotency-key").unwrap().to_string();
+    (batch.into_iter().map(|e| e.event_id).collect(), key)
+}
+
+#[test]
+fn track_assigns_unique_event_ids() {
+    let (endpoint, rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    client.track(test_event());
+    client.track(test_event());
+    let mut preset = test_event();
+    preset.event_id = "evt_from_caller".to_string();
+    client.track(preset);
+    client.flush();
+
+    let (ids, key) = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(ids.len(), 3);
+    assert_eq!(ids[0].len(), 36);
+    assert_ne!(ids[0], ids[1]);
+    assert_eq!(ids[2], "evt_from_caller");
+    assert_eq!(key.len(), 36);
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn retried_batch_keeps_its_idempotency_key() {
+    let (endpoint, rx) = mock_server(vec![
+        http_response("503 Service Unavailable", ""),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    client.track(test_event());
+    client.track(test_event());
+    client.flush();
+    let first = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(client.buffer_len(), 2);
+
+    // Wait out the first backoff (at most BASE_BACKOFF)
+    std::thread::sleep(Duration::from_millis(1100));
+    client.flush();
+    let second = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(first, second);
+    assert_eq!(client.buffer_len(), 0);
+    assert_eq!(errors.lock().unwrap().len(), 1);
+    client.shutdown();
+}
+
+#[test]
+fn events_tracked_during_backoff_do_not_join_the_failed_batch() {
+    let (endpoint, rx) = mock_server(vec![
+        http_response("503 Service Unavailable", ""),
+        http_response("200 OK", ""),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    client.track(test_event());
+    client.flush();
+    let (failed_ids, failed_key) = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+
+    // Tracked while the failed batch waits out its backoff
+    client.track(test_event());
+    assert_eq!(client.buffer_len(), 2);
+    std::thread::sleep(Duration::from_millis(1100));
+    client.flush();
+
+    let retried = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(retried, (failed_ids.clone(), failed_key.clone()));
+    let (new_ids, new_key) = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(new_ids.len(), 1);
+    assert_ne!(new_ids, failed_ids);
+    assert_ne!(new_key, failed_key);
+    assert_eq!(client.buffer_len(), 0);
+    assert_eq!(errors.lock().unwrap().len(), 1);
+    client.shutdown();
+}
+
+#[test]
+fn recovered_batch_keeps_its_idempotency_key() {
+    let (endpoint, rx) = mock_server(vec![http_response("503 Service Unavailable", "")]);
+    let (client, _errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+    client.track(test_event());
+    client.flush();
+    let first = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    client.shutdown();
+
+    let path = dir.path().join("events.jsonl");
+    assert!(path.exists());
+    let (endpoint, rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.storage_path = Some(path.to_str().unwrap().to_string());
+    });
+    assert_eq!(client.buffer_len(), 1);
+    client.flush();
+    let second = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(first, second);
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn idempotency_key_cannot_be_overridden() {
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.headers
+        .insert("Idempotency-Key".to_string(), "fixed".to_string());
+    let err = PeekApiClient::new(opts).err().unwrap();
+    assert!(err.contains("reserved"), "{err}");
+}
+
+fn json_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn delivered_paths(request: &str) -> Vec<String> {
+    let (_, body) = request.split_once("\r\n\r\n").unwrap();
+    let batch: Vec<RequestEvent> = serde_json::from_str(body).unwrap();
+    batch.into_iter().map(|e| e.path).collect()
+}
+
+fn tracked(client: &PeekApiClient, paths: &[&str]) {
+    for path in paths {
+        let mut event = test_event();
+        event.path = path.to_string();
+        client.track(event);
+    }
+}
+
+#[test]
+fn partial_acceptance_drops_rejected_and_retries_transient_events() {
+    let (endpoint, rx) = mock_server(vec![
+        json_response(
+            "200 OK",
+            r#"{"accepted": 1, "rejected": [
+                {"index": 1, "reason": "invalid status_code"},
+                {"index": 2, "reason": "storage timeout", "retryable": true}
+            ]}"#,
+        ),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    tracked(&client, &["/ok", "/bad", "/slow"]);
+    client.flush();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(client.rejected_events(), 1);
+    assert_eq!(client.buffer_len(), 1);
+    {
+        let errors = errors.lock().unwrap();
+        assert!(
+            errors[0].contains("rejected 1 events: invalid status_code"),
+            "{errors:?}"
+        );
+        assert!(errors[1].contains("deferred 1 of 3"), "{errors:?}");
+    }
+
+    std::thread::sleep(Duration::from_millis(1100));
+    client.flush();
+    let retried = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(delivered_paths(&retried), ["/slow"]);
+    assert_eq!(client.buffer_len(), 0);
+    client.shutdown();
+    assert!(!dir.path().join("events.jsonl").exists());
+}
+
+#[test]
+fn bad_request_naming_events_resends_the_rest() {
+    let (endpoint, rx) = mock_server(vec![
+        json_response(
+            "400 Bad Request",
+            r#"{"rejected": [{"index": 0, "reason": "path too long"}]}"#,
+        ),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    tracked(&client, &["/poison", "/a", "/b"]);
+    client.flush();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(client.rejected_events(), 1);
+    assert_eq!(client.buffer_len(), 2);
+    assert!(!dir.path().join("events.jsonl").exists());
+
+    std::thread::sleep(Duration::from_millis(1100));
+    client.flush();
+    let retried = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(delivered_paths(&retried), ["/a", "/b"]);
+    assert_eq!(errors.lock().unwrap().len(), 2);
+    client.shutdown();
+}
+
+#[test]
+fn bad_request_with_only_transient_rejections_retries_the_batch() {
+    let (endpoint, rx) = mock_server(vec![
+        json_response(
+            "409 Conflict",
+            r#"{"rejected": [{"index": 1, "reason": "storage timeout", "retryable": true}]}"#,
+        ),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    tracked(&client, &["/a", "/b"]);
+    client.flush();
+    let first = delivered_ids(&rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(client.rejected_events(), 0);
+    assert_eq!(client.buffer_len(), 2);
+    assert!(client.dead_letters().unwrap().is_empty());
+    assert!(errors.lock().unwrap()[0].contains("deferred 2 of 2"));
+
+    std::thread::sleep(Duration::from_millis(1100));
+    client.flush();
+    let retried = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(delivered_paths(&retried), ["/a", "/b"]);
+    assert_eq!(delivered_ids(&retried), first);
+    assert_eq!(client.buffer_len(), 0);
+    client.shutdown();
+    assert!(!dir.path().join("events.jsonl").exists());
+}
+
+#[test]
+fn bad_request_without_event_results_dead_letters_the_batch() {
+    let (endpoint, _rx) = mock_server(vec![json_response(
+        "400 Bad Request",
+        r#"{"error": "malformed batch"}"#,
+    )]);
+    let (client, errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    tracked(&client, &["/a", "/b"]);
+    client.flush();
+    assert_eq!(client.rejected_events(), 0);
+    assert!(errors.lock().unwrap()[0].contains("400"));
+    assert!(!dir.path().join("events.jsonl").exists());
+
+    let letters = client.dead_letters().unwrap();
+    assert_eq!(letters.len(), 2);
+    assert_eq!(letters[0].reason, DeadLetterReason::ClientError);
+    assert_eq!(letters[0].detail, "Ingestion API returned 400");
+    assert_eq!(letters[1].event.path, "/b");
+    assert!(!letters[0].failed_at.is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn auth_and_routing_errors_keep_the_batch() {
+    for status in [
+        "401 Unauthorized",
+        "403 Forbidden",
+        "404 Not Found",
+        "408 Request Timeout",
+        "302 Found",
+    ] {
+        let (endpoint, _rx) = mock_server(vec![http_response(status, "")]);
+        let (client, errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+        tracked(&client, &["/a", "/b"]);
+        client.flush();
+        assert!(errors.lock().unwrap()[0].contains(&status[..3]), "{status}");
+        assert_eq!(client.buffer_len(), 2, "{status}");
+        assert!(client.dead_letters().unwrap().is_empty(), "{status}");
+
+        client.shutdown();
+        assert!(dir.path().join("events.jsonl").exists(), "{status}");
+    }
+}
+
+#[test]
+fn payload_too_large_splits_the_batch() {
+    let (endpoint, rx) = mock_server(vec![
+        http_response("413 Payload Too Large", ""),
+        http_response("200 OK", ""),
+        http_response("413 Payload Too Large", ""),
+        http_response("200 OK", ""),
+        http_response("413 Payload Too Large", ""),
+    ]);
+    let (client, errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+
+    tracked(&client, &["/a", "/b", "/c", "/huge"]);
+    client.flush();
+    let sent: Vec<Vec<String>> = (0..5)
+        .map(|_| delivered_paths(&rx.recv_timeout(Duration::from_secs(5)).unwrap()))
+        .collect();
+    assert_eq!(
+        sent,
+        [
+            vec!["/a", "/b", "/c", "/huge"],
+            vec!["/a", "/b"],
+            vec!["/c", "/huge"],
+            vec!["/c"],
+            vec!["/huge"],
+        ]
+    );
+
+    // A single event that is still too large is dead-lettered
+    let letters = client.dead_letters().unwrap();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].event.path, "/huge");
+    assert_eq!(letters[0].reason, DeadLetterReason::ClientError);
+    assert_eq!(letters[0].detail, "Ingestion API returned 413");
+    assert_eq!(errors.lock().unwrap().len(), 1);
+    assert_eq!(client.buffer_len(), 0);
+    client.shutdown();
+    assert!(!dir.path().join("events.jsonl").exists());
+}
+
+#[test]
+fn dead_letters_are_not_recovered_and_can_be_exported_or_purged() {
+    let (endpoint, _rx) = mock_server(vec![json_response(
+        "200 OK",
+        r#"{"rejected": [{"index": 0, "reason": "invalid path"}]}"#,
+    )]);
+    let (client, _errors, dir) = recording_client(&format!("{endpoint}/ingest"), |_| {});
+    tracked(&client, &["/poison", "/ok"]);
+    client.flush();
+    client.shutdown();
+
+    let dead_letter_path = dir.path().join("events.dead-letter.jsonl");
+    assert!(dead_letter_path.exists());
+    let storage_path = dir.path().join("events.jsonl");
+    let (client, _errors, _dir) = recording_client("http://localhost:9999/ingest", |opts| {
+        opts.storage_path = Some(storage_path.to_str().unwrap().to_string());
+    });
+    client.recover_from_disk();
+    assert_eq!(client.buffer_len(), 0);
+
+    let letters = client.dead_letters().unwrap();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].reason, DeadLetterReason::Rejected);
+    assert_eq!(letters[0].detail, "invalid path");
+    assert_eq!(letters[0].event.path, "/poison");
+
+    let mut exported = Vec::new();
+    assert_eq!(client.export_dead_letters(&mut exported).unwrap(), 1);
+    let line = String::from_utf8(exported).unwrap();
+    let record: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+    assert_eq!(record["reason"], "rejected");
+    assert_eq!(record["event"]["path"], "/poison");
+
+    assert_eq!(client.purge_dead_letters().unwrap(), 1);
+    assert!(client.dead_letters().unwrap().is_empty());
+    assert!(!dead_letter_path.exists());
+    client.shutdown();
+}
+
+#[test]
+fn dead_letter_file_is_capped() {
+    let (endpoint, _rx) = mock_server(vec![http_response("422 Unprocessable Entity", "")]);
+    let dead_dir = tempfile::tempdir().unwrap();
+    let dead_letter_path = dead_dir.path().join("poison.jsonl");
+    let (client, _errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.dead_letter_path = Some(dead_letter_path.to_str().unwrap().to_string());
+        opts.max_dead_letter_bytes = 1024;
+    });
+
+    tracked(&client, &["/a", "/b", "/c", "/d", "/e", "/f", "/g", "/h"]);
+    client.flush();
+    let stored = client.dead_letters().unwrap().len();
+    assert!((1..8).contains(&stored), "{stored}");
+    assert!(std::fs::metadata(&dead_letter_path).unwrap().len() <= 1024);
+    client.shutdown();
+}
+
+#[test]
+fn ndjson_payload_has_one_event_per_line() {
+    let (endpoint, rx) = raw_mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.payload_format = PayloadFormat::Ndjson;
+    });
+    tracked(&client, &["/a", "/b"]);
+    client.flush();
+
+    let (head, body) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(
+        raw_header(&head, "content-type"),
+        Some("application/x-ndjson")
+    );
+    let body = String::from_utf8(body).unwrap();
+    let paths: Vec<String> = body
+        .lines()
+        .map(|line| serde_json::from_str::<RequestEvent>(line).unwrap().path)
+        .collect();
+    assert_eq!(paths, ["/a", "/b"]);
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn message_pack_payload_decodes_to_the_batch() {
+    let (endpoint, rx) = raw_mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.payload_format = PayloadFormat::MessagePack;
+        opts.signing = Some(RequestSigning::new("whsec_test"));
+    });
+    tracked(&client, &["/a", "/b"]);
+    client.flush();
+
+    let (head, body) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(
+        raw_header(&head, "content-type"),
+        Some("application/msgpack")
+    );
+    let batch: Vec<RequestEvent> = rmp_serde::from_slice(&body).unwrap();
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch[1].path, "/b");
+    assert_eq!(batch[0].event_id.len(), 36);
+    // The signature covers the encoded body
+    let timestamp = raw_header(&head, "x-peekapi-timestamp").unwrap();
+    let signature = raw_header(&head, "x-peekapi-signature").unwrap();
+    assert!(verify_signature(
+        b"whsec_test",
+        timestamp,
+        signature,
+        &body,
+        Duration::from_secs(300)
+    )
+    .is_ok());
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn unsupported_payload_format_falls_back_to_json() {
+    let (endpoint, rx) = raw_mock_server(vec![
+        http_response("415 Unsupported Media Type", ""),
+        http_response("200 OK", ""),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.payload_format = PayloadFormat::MessagePack;
+    });
+    tracked(&client, &["/a"]);
+    client.flush();
+    tracked(&client, &["/b"]);
+    client.flush();
+
+    let content_types: Vec<String> = (0..3)
+        .map(|_| {
+            let (head, _) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            raw_header(&head, "content-type").unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(
+        content_types,
+        [
+            "application/msgpack",
+            "application/json",
+            "application/json"
+        ]
+    );
+    assert_eq!(client.buffer_len(), 0);
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn storage_file_with_mixed_formats_is_recovered() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Clients with different storage formats append to the same file
+    let formats = [
+        (PayloadFormat::MessagePack, "/msgpack"),
+        (PayloadFormat::Json, "/json"),
+        (PayloadFormat::Ndjson, "/ndjson"),
+    ];
+    let clients: Vec<_> = formats
+        .iter()
+        .map(|&(format, path_prefix)| {
+            let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+            opts.storage_path = Some(path.clone());
+            opts.flush_interval = Duration::from_secs(60);
+            opts.storage_format = format;
+            let client = PeekApiClient::new(opts).unwrap();
+            tracked(
+                &client,
+                &[&format!("{path_prefix}/1"), &format!("{path_prefix}/2")],
+            );
+            client
+        })
+        .collect();
+    for client in clients {
+        client.shutdown();
+    }
+
+    let (endpoint, rx) = mock_server(vec![
+        http_response("200 OK", ""),
+        http_response("200 OK", ""),
+        http_response("200 OK", ""),
+    ]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.storage_path = Some(path.clone());
+    });
+    assert_eq!(client.buffer_len(), 6);
+    client.flush();
+    let delivered: Vec<Vec<String>> = (0..3)
+        .map(|_| delivered_paths(&rx.recv_timeout(Duration::from_secs(5)).unwrap()))
+        .collect();
+    assert_eq!(
+        delivered,
+        [
+            ["/msgpack/1", "/msgpack/2"],
+            ["/json/1", "/json/2"],
+            ["/ndjson/1", "/ndjson/2"],
+        ]
+    );
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
+
+#[test]
+fn storage_format_is_independent_of_the_wire_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir
+        .path()
+        .join("events.jsonl")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Unreachable endpoint: the batch ends up in the storage file
+    let mut opts = Options::new("ak_test", "http://localhost:9999/ingest");
+    opts.storage_path = Some(path.clone());
+    opts.flush_interval = Duration::from_secs(60);
+    opts.storage_format = PayloadFormat::MessagePack;
+    let client = PeekApiClient::new(opts).unwrap();
+    tracked(&client, &["/a", "/b"]);
+    client.shutdown();
+
+    // Marker byte and length, then the MessagePack record
+    let stored = std::fs::read(&path).unwrap();
+    assert_eq!(stored[0], 0xc1);
+    let record: serde_json::Value = rmp_serde::from_slice(&stored[5..]).unwrap();
+    let batch: Vec<RequestEvent> = serde_json::from_value(record["events"].clone()).unwrap();
+    assert_eq!(batch.len(), 2);
+
+    // Recovered by a client using the default JSON storage format
+    let (endpoint, rx) = mock_server(vec![http_response("200 OK", "")]);
+    let (client, errors, _dir) = recording_client(&format!("{endpoint}/ingest"), |opts| {
+        opts.storage_path = Some(path.clone());
+    });
+    assert_eq!(client.buffer_len(), 2);
+    client.flush();
+    let request = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(delivered_paths(&request), ["/a", "/b"]);
+    assert_eq!(
+        delivered_ids(&request).0,
+        batch.into_iter().map(|e| e.event_id).collect::<Vec<_>>()
+    );
+    assert!(errors.lock().unwrap().is_empty());
+    client.shutdown();
+}
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Regenerates the JWT test fixtures: an RSA and a P-256 key pair, with the
+# public keys as PEM (SubjectPublicKeyInfo).
+set -e
+cd "$(dirname "$0")"
+
+openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out rsa-key.pem
+openssl pkey -in rsa-key.pem -pubout -out rsa-public.pem
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out ec-key.pem
+openssl pkey -in ec-key.pem -pubout -out ec-public.pem
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Regenerates the TLS test fixtures: a private CA, a server certificate for
+# localhost/127.0.0.1 and a client certificate for mTLS, all signed by the CA.
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500 \
+    -subj "/CN=PeekAPI Test CA" -keyout ca-key.pem -out ca.pem \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+issue() {
+    name=$1; subject=$2; ext=$3
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -subj "$subject" -keyout "$name-key.pem" -out "$name.csr"
+    printf '%b' "$ext" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+        -days 36500 -extfile "$name.ext" -out "$name.pem"
+    rm "$name.csr" "$name.ext"
+}
+
+issue server "/CN=localhost" \
+    "basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=serverAuth\nsubjectAltName=DNS:localhost,IP:127.0.0.1\n"
+issue client "/CN=ingest-client" \
+    "basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=clientAuth\n"
+rm -f ca.srl ca-key.pem
+
+# SPKI pins of the certificate keys (used by tls.rs and tls_test.rs)
+for name in ca server client; do
+    printf '%s: sha256/%s\n' "$name" "$(openssl x509 -in "$name.pem" -pubkey -noout \
+        | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64)"
+done
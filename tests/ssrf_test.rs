@@ -52,7 +52,7 @@ fn zero_network() {
 fn public_ipv4() {
     assert!(!is_private_ip("8.8.8.8"));
     assert!(!is_private_ip("1.1.1.1"));
-    assert!(!is_private_ip("203.0.113.1"));
+    assert!(!is_private_ip("93.184.216.34"));
     assert!(!is_private_ip("151.101.1.140"));
 }
 
@@ -122,3 +122,94 @@ fn validate_rejects_malformed() {
     assert!(validate_endpoint("not-a-url").is_err());
     assert!(validate_endpoint("://missing-scheme").is_err());
 }
+
+// ---------------------------------------------------------------------------
+// URL parsing
+// ---------------------------------------------------------------------------
+
+/// Endpoints with the expected outcome: `Ok` or an error containing the text.
+const URL_CASES: &[(&str, Result<(), &str>)] = &[
+    ("https://api.example.com/ingest", Ok(())),
+    ("HTTPS://API.Example.COM/ingest", Ok(())),
+    ("https://api.example.com:8443/v1/events?x=1#frag", Ok(())),
+    ("https://bücher.example/ingest", Ok(())),
+    ("http://localhost:8080/ingest", Ok(())),
+    ("http://[::1]:8080/ingest", Ok(())),
+    // `?` and `#` end the authority
+    ("https://example.com?x=@10.0.0.1", Ok(())),
+    ("https://example.com#@10.0.0.1/", Ok(())),
+    ("https://10.0.0.1#@example.com", Err("private")),
+    ("https://10.0.0.1?@example.com", Err("private")),
+    // Backslash is a path separator for http(s)
+    ("https://10.0.0.1\\@example.com/", Err("private")),
+    // Numeric IPv4 forms
+    ("https://2130706433/", Err("ambiguous")),
+    ("https://0x7f000001/", Err("ambiguous")),
+    ("https://0177.0.0.1/", Err("ambiguous")),
+    ("https://127.1/", Err("ambiguous")),
+    ("https://0x08.8.8.8/", Err("ambiguous")),
+    ("https://8.8.8.8/", Ok(())),
+    // Percent-encoded and IPv6 hosts
+    ("https://%31%30.0.0.1/", Err("ambiguous")),
+    ("https://[::ffff:a00:1]/", Err("private")),
+    ("https://[2001:db8::1]/", Err("private")),
+    // Credentials
+    ("https://user@example.com/", Err("credentials")),
+    ("https://:secret@example.com/", Err("credentials")),
+    ("https://localhost@evil.example/", Err("credentials")),
+    // Scheme and syntax
+    ("ftp://example.com/", Err("scheme")),
+    ("javascript://example.com/", Err("scheme")),
+    ("http://example.com/", Err("HTTPS")),
+    ("https://example.com:99999/", Err("Invalid")),
+    ("https://exa mple.com/", Err("Invalid")),
+    ("https:///ingest", Err("Invalid")),
+];
+
+#[test]
+fn url_parsing_table() {
+    for (endpoint, expected) in URL_CASES {
+        let got = validate_endpoint(endpoint);
+        match (expected, &got) {
+            (Ok(()), Ok(_)) => {}
+            (Err(text), Err(e)) if e.contains(text) => {}
+            _ => panic!("{endpoint}: expected {expected:?}, got {got:?}"),
+        }
+    }
+}
+
+/// Whatever the validator accepts, the HTTP client (which parses with the
+/// `url` crate) must connect to a public host over HTTPS, or to localhost.
+#[test]
+fn accepted_endpoints_agree_with_http_client_parser() {
+    let extra = [
+        "https://example.com.:443/",
+        "https://EXAMPLE.com/%2e%2e/",
+        "https://example.com:/",
+        "https://xn--bcher-kva.example/",
+        "https://[0:0:0:0:0:0:0:1]/",
+        "https://0.0.0.0/",
+        "https://[::]/",
+        "http://LOCALHOST/",
+        "http://127.0.0.1.:80/",
+        "https:example.com/ingest",
+        "https:\\\\10.0.0.1/",
+    ];
+    for endpoint in URL_CASES.iter().map(|(e, _)| *e).chain(extra) {
+        if validate_endpoint(endpoint).is_err() {
+            continue;
+        }
+        let url = url::Url::parse(endpoint).unwrap();
+        let host = match url.host().unwrap() {
+            url::Host::Domain(d) => d.to_string(),
+            url::Host::Ipv4(ip) => ip.to_string(),
+            url::Host::Ipv6(ip) => ip.to_string(),
+        };
+        let local = matches!(host.as_str(), "localhost" | "127.0.0.1" | "::1");
+        assert!(
+            local || (url.scheme() == "https" && !is_private_ip(&host)),
+            "{endpoint} accepted but connects to {}://{host}",
+            url.scheme()
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use peekapi::{Options, PeekApiClient, PemSource, RequestEvent, TlsOptions};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+const SERVER_PIN: &str = "sha256/IBNKDrN/44KkoGcBBI365LY7R4DUKou9nmaX+Bj/hRc=";
+const CA_PIN: &str = "sha256/lH0eVatPoqsHUVC2L22R1vJdbyPh3+zXpQPA8SHDeE4=";
+const CLIENT_PIN: &str = "sha256//DJKILPijSDmWZjF09i1jMuV0ZUdCbjZ+boNb5z6MbE=";
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/tls/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+fn certs(name: &str) -> Vec<CertificateDer<'static>> {
+    CertificateDer::pem_file_iter(fixture(name))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+fn test_event() -> RequestEvent {
+    RequestEvent {
+        method: "GET".to_string(),
+        path: "/api/users".to_string(),
+        status_code: 200,
+        response_time_ms: 42.0,
+        ..Default::default()
+    }
+}
+
+/// Local HTTPS stand-in for the ingestion API, using the fixture CA's
+/// server certificate. Sends the raw request, or the TLS error, per
+/// connection.
+fn tls_server(require_client_cert: bool) -> (String, mpsc::Receiver<Result<String, String>>) {
+    tls_server_sending(certs("server.pem"), require_client_cert)
+}
+
+/// Same as [`tls_server`], presenting `chain` as the server certificates.
+fn tls_server_sending(
+    chain: Vec<CertificateDer<'static>>,
+    require_client_cert: bool,
+) -> (String, mpsc::Receiver<Result<String, String>>) {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .unwrap();
+    let builder = if require_client_cert {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(certs("ca.pem"));
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .unwrap();
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let key = PrivateKeyDer::from_pem_file(fixture("server-key.pem")).unwrap();
+    let config = Arc::new(builder.with_single_cert(chain, key).unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { return };
+            let conn = ServerConnection::new(Arc::clone(&config)).unwrap();
+            let mut tls = StreamOwned::new(conn, stream);
+            let result = read_request(&mut tls).map_err(|e| e.to_string());
+            if result.is_ok() {
+                let _ = tls.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+                let _ = tls.flush();
+            }
+            if tx.send(result).is_err() {
+                return;
+            }
+        }
+    });
+    (format!("https://localhost:{port}/v1/events"), rx)
+}
+
+fn read_request(stream: &mut impl Read) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+        request.push_str(&line);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    request.push_str("\r\n");
+    request.push_str(&String::from_utf8_lossy(&body));
+    Ok(request)
+}
+
+/// Flush one event with `tls` and return the flush errors.
+fn flush_with(endpoint: &str, tls: Option<TlsOptions>) -> Vec<String> {
+    let dir = tempfile::tempdir().unwrap();
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&errors);
+
+    let mut opts = Options::new("ak_test", endpoint);
+    opts.storage_path = Some(
+        dir.path()
+            .join("events.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    );
+    opts.flush_interval = Duration::from_secs(60);
+    opts.tls = tls;
+    opts.on_error = Some(Box::new(move |e| sink.lock().unwrap().push(e.to_string())));
+    let client = PeekApiClient::new(opts).unwrap();
+
+    client.track(test_event());
+    client.flush();
+    let delivered = client.buffer_len() == 0;
+    client.shutdown();
+
+    let errors = errors.lock().unwrap().clone();
+    assert_eq!(delivered, errors.is_empty(), "{errors:?}");
+    errors
+}
+
+fn private_ca() -> TlsOptions {
+    TlsOptions::default().add_root_certificate(PemSource::file(fixture("ca.pem")))
+}
+
+#[test]
+fn default_roots_reject_private_ca() {
+    let (endpoint, rx) = tls_server(false);
+    let errors = flush_with(&endpoint, None);
+    assert!(errors[0].contains("Transport error"), "{}", errors[0]);
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().is_err());
+}
+
+#[test]
+fn custom_root_trusts_private_ca() {
+    let (endpoint, rx) = tls_server(false);
+    assert!(flush_with(&endpoint, Some(private_ca())).is_empty());
+    let request = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert!(request.starts_with("POST /v1/events "));
+    assert!(request.to_lowercase().contains("x-api-key: ak_test"));
+
+    // Inline PEM, without the bundled roots
+    let (endpoint, _rx) = tls_server(false);
+    let ca = std::fs::read(fixture("ca.pem")).unwrap();
+    let tls = TlsOptions::default()
+        .add_root_certificate(PemSource::bytes(ca))
+        .custom_roots_only(true);
+    assert!(flush_with(&endpoint, Some(tls)).is_empty());
+}
+
+#[test]
+fn mutual_tls_presents_client_certificate() {
+    let (endpoint, rx) = tls_server(true);
+    let tls = private_ca().client_certificate(
+        PemSource::file(fixture("client.pem")),
+        PemSource::file(fixture("client-key.pem")),
+    );
+    assert!(flush_with(&endpoint, Some(tls)).is_empty());
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+
+    let (endpoint, rx) = tls_server(true);
+    assert_eq!(flush_with(&endpoint, Some(private_ca())).len(), 1);
+    let err = rx
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap_err();
+    assert!(err.contains("certificate"), "{err}");
+}
+
+#[test]
+fn spki_pins_are_enforced() {
+    let (endpoint, _rx) = tls_server(false);
+    assert!(flush_with(&endpoint, Some(private_ca().pin_spki(SERVER_PIN))).is_empty());
+
+    let (endpoint, _rx) = tls_server(false);
+    let wrong = "sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    let errors = flush_with(&endpoint, Some(private_ca().pin_spki(wrong)));
+    assert!(errors[0].contains("pinned SPKI"), "{}", errors[0]);
+
+    // The root of the verified chain can be pinned too
+    let (endpoint, _rx) = tls_server(false);
+    assert!(flush_with(&endpoint, Some(private_ca().pin_spki(CA_PIN))).is_empty());
+}
+
+#[test]
+fn spki_pins_ignore_certificates_outside_the_verified_chain() {
+    // The server also sends a certificate with the pinned key that is not on
+    // the path to the root
+    let mut chain = certs("server.pem");
+    chain.extend(certs("client.pem"));
+    let (endpoint, _rx) = tls_server_sending(chain, false);
+    let errors = flush_with(&endpoint, Some(private_ca().pin_spki(CLIENT_PIN)));
+    assert!(errors[0].contains("pinned SPKI"), "{}", errors[0]);
+}
+
+#[test]
+fn invalid_tls_options_fail_construction() {
+    let mut opts = Options::new("ak_test", "https://ingest.example.com/v1/events");
+    opts.tls = Some(TlsOptions::default().pin_spki("md5/abc"));
+    let err = PeekApiClient::new(opts).err().unwrap();
+    assert!(err.contains("Invalid SPKI pin"), "{err}");
+}